@@ -0,0 +1,73 @@
+use std::path::PathBuf;
+
+pub const USAGE: &str = "Usage: yawada [options] [<command>]
+
+Without a command the window is opened. Commands print JSON and share the window's config:
+  daemon                               Update the sources on schedule and listen on the
+                                       control socket, see contrib/yawada.service
+  update [<url>...]                    Update the enabled sources, or only the given ones
+  install                              Install or update the managed section of the hosts file
+  uninstall                            Remove the managed section from the hosts file
+  status                               Show the state of the hosts file and the sources
+  explain <host>                       Show why a host is or isn't blocked
+  sources list|add <url>|remove <url>
+  allowed list|add <host> [exact|subdomains|glob|regex]|remove <host>
+  redirected list|add <host> <ip>|remove <host>
+  blocked list|add <host> [<note>]|remove <host>
+  patterns list|add <regex>|remove <regex>
+
+Exit codes: 0 success, 1 error, 2 bad usage, 3 done but something needs attention
+(a source failed to update, or the hosts file isn't up to date).
+
+Options:
+  --hosts-file <path>  Manage this file instead of the system hosts file
+  --root <dir>         Manage the hosts file under an alternate root (e.g. a chroot)
+  --portable           Keep the config and data next to the executable
+  --headless           Same as the daemon command
+  -h, --help           Show this help";
+
+#[derive(Default)]
+pub struct Args {
+    pub hosts_file: Option<PathBuf>,
+    pub root: Option<PathBuf>,
+    pub portable: bool,
+    pub help: bool,
+    pub headless: bool,
+    // Command line subcommand and its arguments, empty opens the window
+    pub command: Vec<String>,
+    // Run as the privileged helper, this is only used by yawada itself
+    pub helper: bool,
+}
+
+impl Args {
+    /// Parses the command line arguments, without the program name.
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+        let mut parsed = Args::default();
+        while let Some(arg) = args.next() {
+            // Accept both --flag value and --flag=value
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => {
+                    (flag.to_string(), Some(value.to_string()))
+                }
+                _ => (arg, None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("Missing value for {}", flag))
+            };
+            match flag.as_str() {
+                "--hosts-file" => parsed.hosts_file = Some(PathBuf::from(value()?)),
+                "--root" => parsed.root = Some(PathBuf::from(value()?)),
+                "--portable" => parsed.portable = true,
+                "--headless" => parsed.headless = true,
+                "-h" | "--help" => parsed.help = true,
+                "helper" if parsed.command.is_empty() => parsed.helper = true,
+                _ if !flag.starts_with('-') => parsed.command.push(flag),
+                _ => return Err(format!("Unknown argument: {}", flag)),
+            }
+        }
+        Ok(parsed)
+    }
+}
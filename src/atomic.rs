@@ -0,0 +1,200 @@
+use serde::de::DeserializeOwned;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// How many random temporary file names are tried before giving up
+const TEMP_ATTEMPTS: usize = 16;
+
+// Appends a suffix to the file name, e.g. hosts -> hosts.bak
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(suffix);
+    path.with_file_name(file_name)
+}
+
+/// Returns the path the previous version of a file is kept at.
+pub fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".bak")
+}
+
+// Makes the rename durable, directories can't be synced on Windows
+fn sync_dir(path: &Path) {
+    if cfg!(unix) {
+        if let Some(dir) = path.parent() {
+            if let Ok(dir) = File::open(dir) {
+                let _ = dir.sync_all();
+            }
+        }
+    }
+}
+
+// Part of the temporary file name nobody can guess in advance
+fn random_suffix() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&std::process::id().to_le_bytes());
+    hasher.update(&COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    hasher.update(&nanos.to_le_bytes());
+    hasher.finalize().to_hex()[..16].to_string()
+}
+
+// Creates a temporary file next to `path` under a random name.
+// It's created exclusively and never through a symlink, so a planted file or link
+// can't make us write anywhere else.
+fn create_temp(path: &Path) -> io::Result<(PathBuf, File)> {
+    for _ in 0..TEMP_ATTEMPTS {
+        let tmp_path = with_suffix(path, &format!(".{}.tmp", random_suffix()));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.custom_flags(libc::O_NOFOLLOW).mode(0o600);
+        }
+        match options.open(&tmp_path) {
+            Ok(file) => return Ok((tmp_path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        ErrorKind::AlreadyExists,
+        format!("No free temporary file name next to {}", path.display()),
+    ))
+}
+
+// Writes a temporary file and renames it over `path`, with the permissions of `permissions_of`.
+// The rename replaces a symlink at `path` instead of writing through it.
+fn replace(path: &Path, contents: &[u8], permissions_of: &Path) -> io::Result<()> {
+    let (tmp_path, mut file) = create_temp(path)?;
+    let result = (|| {
+        file.write_all(contents)?;
+        match fs::symlink_metadata(permissions_of) {
+            Ok(metadata) if metadata.is_file() => file.set_permissions(metadata.permissions())?,
+            // New files get the usual permissions instead of the private ones of the temporary file
+            #[cfg(unix)]
+            _ => {
+                use std::os::unix::fs::PermissionsExt;
+                file.set_permissions(fs::Permissions::from_mode(0o644))?;
+            }
+            #[cfg(not(unix))]
+            _ => {}
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        sync_dir(path);
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Writes `contents` to `path` without ever leaving a half-written file behind.
+///
+/// The contents go to a temporary file next to `path`, which is synced to disk
+/// and then renamed over `path`.
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    replace(path, contents.as_ref(), path)
+}
+
+// Keeps the current version of `path` as `<path>.bak`. The backup is written like any other
+// file, so an existing `.bak`, even a symlink, is replaced and never written through.
+fn backup(path: &Path) -> io::Result<()> {
+    match fs::read(path) {
+        Ok(previous) => replace(&backup_path(path), &previous, path),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Like [`write`], but keeps the previous version of the file as `<path>.bak`.
+pub fn write_with_backup(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    backup(path)?;
+    write(path, contents)
+}
+
+// Renaming over a bind-mounted file fails with one of these, the mount point can't be replaced
+fn is_mount_point_error(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        matches!(e.raw_os_error(), Some(libc::EBUSY) | Some(libc::EXDEV))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = e;
+        false
+    }
+}
+
+/// Like [`write_with_backup`], for hosts files, which may be bind-mounted into a container.
+///
+/// When the file can't be renamed over because it's a mount point, it's rewritten in place
+/// instead. Only then can an interrupted write leave it truncated, with the previous version
+/// still in the `.bak`.
+pub fn rewrite_with_backup(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    backup(path)?;
+    match replace(path, contents.as_ref(), path) {
+        Err(e) if is_mount_point_error(&e) => {
+            eprintln!(
+                "Can't replace {} ({}), rewriting it in place",
+                path.display(),
+                e
+            );
+        }
+        result => return result,
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(contents.as_ref())?;
+    file.sync_all()
+}
+
+/// Loads a JSON file, falling back to its `.bak` if it can't be read or parsed.
+///
+/// Returns `None` if neither exists, and the error of the primary file if both are unusable.
+pub fn load_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, String> {
+    let load = |path: &Path| -> Result<Option<T>, String> {
+        match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map(Some)
+                .map_err(|e| e.to_string()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    };
+    let error = match load(path) {
+        Ok(Some(value)) => return Ok(Some(value)),
+        Ok(None) => None,
+        Err(e) => Some(e),
+    };
+    let backup_path = backup_path(path);
+    match load(&backup_path) {
+        Ok(Some(value)) => {
+            eprintln!(
+                "Failed to load {}, loaded {} instead",
+                path.display(),
+                backup_path.display()
+            );
+            Ok(Some(value))
+        }
+        _ => match error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        },
+    }
+}
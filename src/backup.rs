@@ -0,0 +1,169 @@
+use crate::{atomic, config, PROJECT_DIRS};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackupSettings {
+    // How many snapshots to keep, 0 keeps all of them
+    pub retention: usize,
+}
+
+impl Default for BackupSettings {
+    fn default() -> Self {
+        Self { retention: 10 }
+    }
+}
+
+#[derive(Clone)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub timestamp: u64,
+    pub digest: String,
+    pub size: u64,
+}
+
+/// Returns the directory the snapshots of the hosts file at `path` are stored in.
+///
+/// Every hosts file gets its own directory, so a snapshot is only ever restored onto
+/// the file it was taken of.
+pub fn backups_dir(path: &Path) -> PathBuf {
+    let dir_name = blake3::hash(path.as_os_str().as_encoded_bytes())
+        .to_hex()
+        .to_string();
+    PROJECT_DIRS.data_dir().join("backups").join(dir_name)
+}
+
+/// Loads the backup settings from the config.
+pub fn load_settings() -> Result<BackupSettings, String> {
+    config::load().map(|config| config.backup)
+}
+
+/// Saves the backup settings into the config.
+pub fn save_settings(settings: &BackupSettings) -> Result<(), String> {
+    config::update(|config| config.backup = settings.clone())
+}
+
+/// Lists the snapshots of the hosts file at `path`, newest first.
+pub fn list_snapshots(path: &Path) -> Vec<Snapshot> {
+    let entries = match fs::read_dir(backups_dir(path)) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let mut snapshots: Vec<Snapshot> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            // Snapshots are named <timestamp>-<blake3 digest>.hosts
+            let file_name = entry.file_name().into_string().ok()?;
+            let (timestamp, digest) = file_name.strip_suffix(".hosts")?.split_once('-')?;
+            // Skip stray files, the window shows the date and the start of the digest
+            let timestamp: u64 = timestamp.parse().ok()?;
+            DateTime::from_timestamp(i64::try_from(timestamp).ok()?, 0)?;
+            if digest.len() != 64
+                || !digest
+                    .bytes()
+                    .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+            {
+                return None;
+            }
+            Some(Snapshot {
+                path: entry.path(),
+                timestamp,
+                digest: digest.to_string(),
+                size: entry.metadata().ok()?.len(),
+            })
+        })
+        .collect();
+    snapshots.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    snapshots
+}
+
+/// Copies the hosts file at `path` into the backups directory.
+///
+/// Nothing is written if the file doesn't exist or is identical to the newest snapshot.
+pub fn snapshot(path: &Path) -> Result<Option<Snapshot>, String> {
+    let content = match fs::read(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+    let digest = blake3::hash(&content).to_hex().to_string();
+    if let Some(latest) = list_snapshots(path).first() {
+        if latest.digest == digest {
+            return Ok(None);
+        }
+    }
+    let dir = backups_dir(path);
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let snapshot_path = dir.join(format!("{}-{}.hosts", timestamp, digest));
+    atomic::write(&snapshot_path, &content)
+        .map_err(|e| format!("Failed to write {}: {}", snapshot_path.display(), e))?;
+    Ok(Some(Snapshot {
+        path: snapshot_path,
+        timestamp,
+        digest,
+        size: content.len() as u64,
+    }))
+}
+
+/// Deletes the oldest snapshots of the hosts file at `path` so that at most `retention` are left.
+pub fn prune(path: &Path, retention: usize) {
+    if retention == 0 {
+        return;
+    }
+    for snapshot in list_snapshots(path).iter().skip(retention) {
+        match fs::remove_file(&snapshot.path) {
+            Ok(_) => eprintln!("Pruned snapshot {}", snapshot.path.display()),
+            Err(e) => eprintln!("Failed to prune {}: {}", snapshot.path.display(), e),
+        }
+    }
+}
+
+/// Takes a snapshot of the hosts file at `path`, then prunes the old ones.
+pub fn snapshot_and_prune(path: &Path, retention: usize) -> Result<(), String> {
+    if let Some(snapshot) = snapshot(path)? {
+        eprintln!("Saved snapshot {}", snapshot.path.display());
+    }
+    prune(path, retention);
+    Ok(())
+}
+
+/// Reads a snapshot, checking that its content still matches the digest in its name.
+pub fn read_snapshot(snapshot: &Snapshot) -> Result<Vec<u8>, String> {
+    let content = fs::read(&snapshot.path)
+        .map_err(|e| format!("Failed to read {}: {}", snapshot.path.display(), e))?;
+    if blake3::hash(&content).to_hex().as_str() != snapshot.digest {
+        return Err(format!(
+            "Snapshot {} is corrupted, its digest doesn't match",
+            snapshot.path.display()
+        ));
+    }
+    Ok(content)
+}
+
+/// Compares two files line by line.
+///
+/// Hosts files are too large for a proper diff, so this only returns the lines
+/// that were removed (`-`) from `old` and added (`+`) in `new`, in file order.
+pub fn diff(old: &str, new: &str) -> Vec<String> {
+    let old_lines: HashSet<&str> = old.lines().collect();
+    let new_lines: HashSet<&str> = new.lines().collect();
+    let removed = old
+        .lines()
+        .filter(|l| !new_lines.contains(l))
+        .map(|l| format!("- {}", l));
+    let added = new
+        .lines()
+        .filter(|l| !old_lines.contains(l))
+        .map(|l| format!("+ {}", l));
+    removed.chain(added).collect()
+}
@@ -0,0 +1,548 @@
+use crate::fetch::{self, FetchStatus};
+use crate::rules::{self, MatchMode};
+use crate::settings::{self, Settings};
+use crate::{
+    backup, config, daemon, helper, hosts, install, network, schedule, AllowedHost, BlockedHost,
+    BlockedPattern, HostsSource, RedirectedHost,
+};
+use eframe::egui;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::thread;
+use std::time::Duration;
+
+const EXIT_OK: i32 = 0;
+// The command couldn't be carried out
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+// The command went through, but something needs attention
+const EXIT_ATTENTION: i32 = 3;
+
+enum Error {
+    Usage(String),
+    Failed(String),
+}
+
+impl From<String> for Error {
+    fn from(e: String) -> Self {
+        Error::Failed(e)
+    }
+}
+
+// Output of a command and its exit code
+type Outcome = Result<(Value, i32), Error>;
+
+/// Runs a command line subcommand, printing its result as JSON.
+///
+/// Returns the exit code.
+pub fn run(command: &[String]) -> i32 {
+    let args: Vec<&str> = command.iter().map(String::as_str).collect();
+    let outcome = match args.as_slice() {
+        ["update", urls @ ..] => update(urls),
+        ["install"] => install(),
+        ["uninstall"] => uninstall(),
+        ["status"] => status(),
+        ["explain", host] => explain(host),
+        ["sources", action @ ..] => sources(action),
+        ["allowed", action @ ..] => allowed(action),
+        ["redirected", action @ ..] => redirected(action),
+        ["blocked", action @ ..] => blocked(action),
+        ["patterns", action @ ..] => patterns(action),
+        _ => Err(Error::Usage(format!(
+            "Unknown command: {}",
+            command.join(" ")
+        ))),
+    };
+    let (output, code) = match outcome {
+        Ok(result) => result,
+        Err(Error::Usage(e)) => (json!({ "error": e }), EXIT_USAGE),
+        Err(Error::Failed(e)) => (json!({ "error": e }), EXIT_ERROR),
+    };
+    println!("{}", serde_json::to_string_pretty(&output).unwrap());
+    code
+}
+
+fn load_config() -> Result<config::Config, String> {
+    config::load().map_err(|e| format!("Failed to load the config: {}", e))
+}
+
+fn load_settings() -> Result<Settings, String> {
+    settings::load().map_err(|e| format!("Failed to load settings: {}", e))
+}
+
+// Compiles the managed section from the blocked hosts and the rules
+fn compile(blocked_hosts: &BTreeSet<String>, settings: &Settings) -> Result<String, String> {
+    let config = load_config()?;
+    Ok(install::compile_hosts(
+        blocked_hosts,
+        &config.allowed_hosts,
+        &config.redirected_hosts,
+        settings,
+    ))
+}
+
+fn status_json(url: &str, status: &FetchStatus) -> Value {
+    match status {
+        FetchStatus::Done { bytes, fetched_at } => json!({
+            "url": url,
+            "status": "updated",
+            "bytes": bytes,
+            "fetched_at": fetched_at,
+        }),
+        FetchStatus::Unchanged { checked_at } => json!({
+            "url": url,
+            "status": "unchanged",
+            "checked_at": checked_at,
+        }),
+        FetchStatus::Rejected(e) => json!({ "url": url, "status": "rejected", "error": e }),
+        FetchStatus::Failed(e) => json!({ "url": url, "status": "failed", "error": e }),
+        FetchStatus::Cancelled => json!({ "url": url, "status": "cancelled" }),
+        FetchStatus::Queued | FetchStatus::Downloading { .. } => {
+            json!({ "url": url, "status": "pending" })
+        }
+    }
+}
+
+fn update(urls: &[&str]) -> Outcome {
+    let config = load_config()?;
+    let settings = config.settings;
+    network::configure(&settings)?;
+    let sources = config.hosts_sources;
+    let selected: Vec<HostsSource> = if urls.is_empty() {
+        sources.iter().filter(|s| s.enabled).cloned().collect()
+    } else {
+        let mut selected = vec![];
+        for url in urls {
+            match sources.iter().find(|s| s.url == *url) {
+                Some(source) => selected.push(source.clone()),
+                None => return Err(Error::Failed(format!("Unknown source: {}", url))),
+            }
+        }
+        selected
+    };
+    let results = if daemon::status().is_some() {
+        // Let the daemon do it, it owns the hosts sources file while it runs
+        let urls = selected.into_iter().map(|s| s.url).collect();
+        daemon::request(&daemon::Request::UpdateNow { urls })?;
+        loop {
+            thread::sleep(Duration::from_millis(500));
+            let status = daemon::request(&daemon::Request::Status)?;
+            if !status.updating {
+                break status.progress;
+            }
+        }
+    } else {
+        let results = fetch::FetchJob::start(
+            &egui::Context::default(),
+            selected,
+            network::RetryPolicy::from_settings(&settings),
+        )
+        .wait();
+        schedule::record_results(&results)?;
+        results
+    };
+    let changed = results
+        .iter()
+        .any(|(_, status)| matches!(status, FetchStatus::Done { .. }));
+    let all_good = results.iter().all(|(_, status)| {
+        matches!(
+            status,
+            FetchStatus::Done { .. } | FetchStatus::Unchanged { .. }
+        )
+    });
+    let output = json!({
+        "changed": changed,
+        "sources": results
+            .iter()
+            .map(|(url, status)| status_json(url, status))
+            .collect::<Vec<_>>(),
+    });
+    Ok((output, if all_good { EXIT_OK } else { EXIT_ATTENTION }))
+}
+
+fn install() -> Outcome {
+    let settings = load_settings()?;
+    let hosts_path = install::hosts_path(&settings);
+    let config = load_config()?;
+    let (blocked_hosts, _) = hosts::load_blocked_hosts(
+        &config.hosts_sources,
+        &config.blocked_hosts,
+        &config.blocked_patterns,
+    );
+    let compiled = compile(&blocked_hosts, &settings)?;
+    let entries = compiled.lines().count();
+    let changed =
+        install::read_install_state(&hosts_path, &compiled) != install::InstallState::Current;
+    if changed {
+        let retention = backup::load_settings()?.retention;
+        backup::snapshot_and_prune(&hosts_path, retention)?;
+        helper::perform(&helper::Request::install(hosts_path.clone(), compiled))?;
+    }
+    let output = json!({
+        "changed": changed,
+        "hosts_file": hosts_path,
+        "entries": entries,
+    });
+    Ok((output, EXIT_OK))
+}
+
+fn uninstall() -> Outcome {
+    let settings = load_settings()?;
+    let hosts_path = install::hosts_path(&settings);
+    let content = std::fs::read_to_string(&hosts_path).unwrap_or_default();
+    let changed = install::find_managed_section(&content)?.is_some();
+    if changed {
+        let retention = backup::load_settings()?.retention;
+        backup::snapshot_and_prune(&hosts_path, retention)?;
+        helper::perform(&helper::Request::uninstall(hosts_path.clone()))?;
+    }
+    let output = json!({
+        "changed": changed,
+        "hosts_file": hosts_path,
+    });
+    Ok((output, EXIT_OK))
+}
+
+fn status() -> Outcome {
+    let settings = load_settings()?;
+    let hosts_path = install::hosts_path(&settings);
+    let config = load_config()?;
+    let (blocked_hosts, stats) = hosts::load_blocked_hosts(
+        &config.hosts_sources,
+        &config.blocked_hosts,
+        &config.blocked_patterns,
+    );
+    let sources = config.hosts_sources;
+    let compiled = compile(&blocked_hosts, &settings)?;
+    let state = install::read_install_state(&hosts_path, &compiled);
+    let sources: Vec<Value> = sources
+        .iter()
+        .map(|source| {
+            let stats = stats.get(&source.url);
+            json!({
+                "url": source.url,
+                "enabled": source.enabled,
+                "format": source.format,
+                "last_updated": source.last_updated,
+                "last_checked": source.last_checked,
+                "hosts": stats.map(|s| s.hosts),
+                "rejected_lines": stats.map(|s| s.rejected_lines),
+                "exceptions": stats.map(|s| s.exceptions),
+                "ignored_rules": stats.map(|s| s.ignored_rules),
+                "verified": stats.is_some_and(|s| s.verified),
+                "rejected_update": stats.and_then(|s| s.rejected_update.clone()),
+            })
+        })
+        .collect();
+    let output = json!({
+        "hosts_file": hosts_path,
+        "state": state,
+        "blocked_hosts": blocked_hosts.len(),
+        "entries": compiled.lines().count(),
+        "sources": sources,
+    });
+    let code = if state == install::InstallState::Current {
+        EXIT_OK
+    } else {
+        EXIT_ATTENTION
+    };
+    Ok((output, code))
+}
+
+fn explain(host: &str) -> Outcome {
+    let host = parse_host(host)?;
+    let config = load_config()?;
+    let blocked_by = hosts::sources_blocking(&config.hosts_sources, &host);
+    let excepted_by = hosts::sources_excepting(&config.hosts_sources, &host);
+    // Block patterns only add hosts some source knows of
+    let mut blocked_by_patterns: Vec<&str> = config
+        .blocked_patterns
+        .iter()
+        .filter(|p| p.enabled)
+        .filter(|p| rules::compile_regex(&p.pattern).is_ok_and(|regex| regex.is_match(&host)))
+        .map(|p| p.pattern.as_str())
+        .collect();
+    if !blocked_by_patterns.is_empty()
+        && !hosts::load_known_hosts(&config.hosts_sources).contains(&host)
+    {
+        blocked_by_patterns.clear();
+    }
+    let custom = config
+        .blocked_hosts
+        .iter()
+        .any(|h| h.enabled && h.host == host);
+    // Blocked by the user, which wins over the exceptions of the sources
+    let user_blocked = custom || !blocked_by_patterns.is_empty();
+    let allowed = config.allowed_hosts.iter().any(|h| {
+        h.enabled && rules::Rule::new(&h.host, h.mode).is_ok_and(|rule| rule.matches(&host))
+    });
+    let redirected_to = config
+        .redirected_hosts
+        .iter()
+        .find(|h| h.enabled && h.host == host)
+        .map(|h| h.ip.clone());
+    // Same order as compile_hosts: redirects win, then allow rules, then custom blocked hosts
+    // and block patterns, then the exceptions and the sources
+    let result = if redirected_to.is_some() {
+        "redirected"
+    } else if allowed && (!blocked_by.is_empty() || user_blocked) {
+        "allowed"
+    } else if user_blocked {
+        "blocked"
+    } else if !excepted_by.is_empty() && !blocked_by.is_empty() {
+        "allowed"
+    } else if !blocked_by.is_empty() {
+        "blocked"
+    } else {
+        "not_blocked"
+    };
+    let output = json!({
+        "host": host,
+        "result": result,
+        "blocked_by": blocked_by,
+        "excepted_by": excepted_by,
+        "blocked_by_patterns": blocked_by_patterns,
+        "custom": custom,
+        "allowed": allowed,
+        "redirected_to": redirected_to,
+    });
+    Ok((output, EXIT_OK))
+}
+
+fn sources(action: &[&str]) -> Outcome {
+    if action == ["list"] {
+        return Ok((json!(load_config()?.hosts_sources), EXIT_OK));
+    }
+    // Looked up and changed under the config lock, so parallel runs don't lose changes
+    let changed = config::update(|config| -> Result<bool, Error> {
+        let sources = &mut config.hosts_sources;
+        Ok(match action {
+            ["add", url] => {
+                if sources.iter().any(|s| s.url == *url) {
+                    false
+                } else {
+                    sources.push(HostsSource {
+                        url: url.to_string(),
+                        ..Default::default()
+                    });
+                    true
+                }
+            }
+            ["remove", url] => {
+                let len = sources.len();
+                sources.retain(|s| s.url != *url);
+                sources.len() != len
+            }
+            _ => {
+                return Err(Error::Usage(
+                    "Usage: sources list|add <url>|remove <url>".into(),
+                ))
+            }
+        })
+    })??;
+    if changed {
+        daemon::reload_config(&mut daemon::status());
+    }
+    Ok((json!({ "changed": changed }), EXIT_OK))
+}
+
+// Lowercases a host given on the command line and checks it
+fn parse_host(host: &str) -> Result<String, Error> {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if !hosts::is_valid_hostname(&host) {
+        return Err(Error::Usage(format!("Invalid hostname: {}", host)));
+    }
+    Ok(host)
+}
+
+fn allowed(action: &[&str]) -> Outcome {
+    if action == ["list"] {
+        return Ok((json!(load_config()?.allowed_hosts), EXIT_OK));
+    }
+    let changed =
+        config::update(|config| -> Result<bool, Error> {
+            let allowed_hosts = &mut config.allowed_hosts;
+            Ok(match action {
+                ["add", host, mode @ ..] if mode.len() <= 1 => {
+                    let mode = match mode.first() {
+                        Some(name) => MatchMode::from_name(name)
+                            .ok_or_else(|| Error::Usage(format!("Unknown match mode: {}", name)))?,
+                        None => MatchMode::Exact,
+                    };
+                    let host = rules::normalize_pattern(host, mode);
+                    rules::validate_pattern(&host, mode).map_err(Error::Usage)?;
+                    match allowed_hosts.iter_mut().find(|h| h.host == host) {
+                        Some(allowed_host) if allowed_host.mode == mode && allowed_host.enabled => {
+                            false
+                        }
+                        Some(allowed_host) => {
+                            allowed_host.mode = mode;
+                            allowed_host.enabled = true;
+                            true
+                        }
+                        None => {
+                            allowed_hosts.push(AllowedHost {
+                                host,
+                                enabled: true,
+                                mode,
+                            });
+                            true
+                        }
+                    }
+                }
+                ["remove", host] => {
+                    let len = allowed_hosts.len();
+                    allowed_hosts.retain(|h| h.host != rules::normalize_pattern(host, h.mode));
+                    allowed_hosts.len() != len
+                }
+                _ => return Err(Error::Usage(
+                    "Usage: allowed list|add <host> [exact|subdomains|glob|regex]|remove <host>"
+                        .into(),
+                )),
+            })
+        })??;
+    if changed {
+        daemon::reload_config(&mut daemon::status());
+    }
+    Ok((json!({ "changed": changed }), EXIT_OK))
+}
+
+fn redirected(action: &[&str]) -> Outcome {
+    if action == ["list"] {
+        return Ok((json!(load_config()?.redirected_hosts), EXIT_OK));
+    }
+    let changed = config::update(|config| -> Result<bool, Error> {
+        let redirected_hosts = &mut config.redirected_hosts;
+        Ok(match action {
+            ["add", host, ip] => {
+                let host = parse_host(host)?;
+                let ip = ip
+                    .parse::<IpAddr>()
+                    .map_err(|_| Error::Usage(format!("Invalid IP address: {}", ip)))?
+                    .to_string();
+                match redirected_hosts.iter_mut().find(|h| h.host == host) {
+                    Some(redirected_host)
+                        if redirected_host.ip == ip && redirected_host.enabled =>
+                    {
+                        false
+                    }
+                    Some(redirected_host) => {
+                        redirected_host.ip = ip;
+                        redirected_host.enabled = true;
+                        true
+                    }
+                    None => {
+                        redirected_hosts.push(RedirectedHost {
+                            host,
+                            ip,
+                            enabled: true,
+                        });
+                        true
+                    }
+                }
+            }
+            ["remove", host] => {
+                let host = parse_host(host)?;
+                let len = redirected_hosts.len();
+                redirected_hosts.retain(|h| h.host != host);
+                redirected_hosts.len() != len
+            }
+            _ => {
+                return Err(Error::Usage(
+                    "Usage: redirected list|add <host> <ip>|remove <host>".into(),
+                ))
+            }
+        })
+    })??;
+    if changed {
+        daemon::reload_config(&mut daemon::status());
+    }
+    Ok((json!({ "changed": changed }), EXIT_OK))
+}
+
+fn blocked(action: &[&str]) -> Outcome {
+    if action == ["list"] {
+        return Ok((json!(load_config()?.blocked_hosts), EXIT_OK));
+    }
+    let changed = config::update(|config| -> Result<bool, Error> {
+        let blocked_hosts = &mut config.blocked_hosts;
+        Ok(match action {
+            ["add", host, note @ ..] if note.len() <= 1 => {
+                let host = parse_host(host)?;
+                let note = note.first().map(|note| note.to_string());
+                match blocked_hosts.iter_mut().find(|h| h.host == host) {
+                    Some(blocked_host) => {
+                        let mut changed = !std::mem::replace(&mut blocked_host.enabled, true);
+                        if let Some(note) = note {
+                            changed |= blocked_host.note != note;
+                            blocked_host.note = note;
+                        }
+                        changed
+                    }
+                    None => {
+                        blocked_hosts.push(BlockedHost {
+                            host,
+                            enabled: true,
+                            note: note.unwrap_or_default(),
+                        });
+                        true
+                    }
+                }
+            }
+            ["remove", host] => {
+                let host = parse_host(host)?;
+                let len = blocked_hosts.len();
+                blocked_hosts.retain(|h| h.host != host);
+                blocked_hosts.len() != len
+            }
+            _ => {
+                return Err(Error::Usage(
+                    "Usage: blocked list|add <host> [<note>]|remove <host>".into(),
+                ))
+            }
+        })
+    })??;
+    if changed {
+        daemon::reload_config(&mut daemon::status());
+    }
+    Ok((json!({ "changed": changed }), EXIT_OK))
+}
+
+fn patterns(action: &[&str]) -> Outcome {
+    if action == ["list"] {
+        return Ok((json!(load_config()?.blocked_patterns), EXIT_OK));
+    }
+    let changed = config::update(|config| -> Result<bool, Error> {
+        let blocked_patterns = &mut config.blocked_patterns;
+        Ok(match action {
+            ["add", pattern] => {
+                rules::compile_regex(pattern).map_err(Error::Usage)?;
+                match blocked_patterns.iter_mut().find(|p| p.pattern == *pattern) {
+                    Some(blocked_pattern) => !std::mem::replace(&mut blocked_pattern.enabled, true),
+                    None => {
+                        blocked_patterns.push(BlockedPattern {
+                            pattern: pattern.to_string(),
+                            enabled: true,
+                        });
+                        true
+                    }
+                }
+            }
+            ["remove", pattern] => {
+                let len = blocked_patterns.len();
+                blocked_patterns.retain(|p| p.pattern != *pattern);
+                blocked_patterns.len() != len
+            }
+            _ => {
+                return Err(Error::Usage(
+                    "Usage: patterns list|add <regex>|remove <regex>".into(),
+                ))
+            }
+        })
+    })??;
+    if changed {
+        daemon::reload_config(&mut daemon::status());
+    }
+    Ok((json!({ "changed": changed }), EXIT_OK))
+}
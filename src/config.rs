@@ -0,0 +1,259 @@
+use crate::backup::BackupSettings;
+use crate::settings::Settings;
+use crate::{
+    atomic, AllowedHost, BlockedHost, BlockedPattern, HostsSource, RedirectedHost, PROJECT_DIRS,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::fs::{self, File, OpenOptions};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the config layout written by this build.
+pub const CONFIG_VERSION: u64 = 1;
+
+// Files the config was split into before version 1, and the key each one moved to
+const LEGACY_FILES: [(&str, &str); 5] = [
+    ("hosts_sources.json", "hosts_sources"),
+    ("allowed_hosts.json", "allowed_hosts"),
+    ("redirected_hosts.json", "redirected_hosts"),
+    ("settings.json", "settings"),
+    ("backup_settings.json", "backup"),
+];
+
+type Document = Map<String, Value>;
+
+// Migrations[i] turns a document of version i into one of version i + 1
+const MIGRATIONS: [fn(&mut Document); 1] = [migrate_v0];
+
+// Version 0 is the legacy files put together under their new keys, nothing else changed
+fn migrate_v0(_document: &mut Document) {}
+
+/// Everything the user configures, stored in a single versioned `config.json`.
+#[derive(Serialize)]
+pub struct Config {
+    pub version: u64,
+    pub settings: Settings,
+    pub backup: BackupSettings,
+    pub hosts_sources: Vec<HostsSource>,
+    pub allowed_hosts: Vec<AllowedHost>,
+    pub redirected_hosts: Vec<RedirectedHost>,
+    pub blocked_hosts: Vec<BlockedHost>,
+    pub blocked_patterns: Vec<BlockedPattern>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            settings: Settings::default(),
+            backup: BackupSettings::default(),
+            hosts_sources: vec![],
+            allowed_hosts: vec![],
+            redirected_hosts: vec![],
+            blocked_hosts: vec![],
+            blocked_patterns: vec![],
+        }
+    }
+}
+
+/// Returns the path of the config file.
+pub fn config_path() -> PathBuf {
+    PROJECT_DIRS.config_dir().join("config.json")
+}
+
+/// Returns the directory the config is backed up to before every migration.
+pub fn migrations_dir() -> PathBuf {
+    PROJECT_DIRS.config_dir().join("migrations")
+}
+
+// Reads a value, falling back to the default if it's missing or unreadable
+fn take<T: DeserializeOwned + Default>(
+    document: &mut Document,
+    key: &str,
+    warnings: &mut Vec<String>,
+) -> T {
+    match document.remove(key) {
+        None | Some(Value::Null) => T::default(),
+        Some(value) => serde_json::from_value(value).unwrap_or_else(|e| {
+            warnings.push(format!("Reset {} to the defaults: {}", key, e));
+            T::default()
+        }),
+    }
+}
+
+// Reads a list entry by entry, so a broken entry doesn't take the whole list with it
+fn take_list<T: DeserializeOwned>(
+    document: &mut Document,
+    key: &str,
+    warnings: &mut Vec<String>,
+) -> Vec<T> {
+    let entries = match document.remove(key) {
+        None | Some(Value::Null) => return vec![],
+        Some(Value::Array(entries)) => entries,
+        Some(_) => {
+            warnings.push(format!("Ignored {}, it isn't a list", key));
+            return vec![];
+        }
+    };
+    entries
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, entry)| match serde_json::from_value(entry) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                warnings.push(format!("Skipped entry {} of {}: {}", i + 1, key, e));
+                None
+            }
+        })
+        .collect()
+}
+
+fn from_document(mut document: Document, warnings: &mut Vec<String>) -> Config {
+    Config {
+        version: CONFIG_VERSION,
+        settings: take(&mut document, "settings", warnings),
+        backup: take(&mut document, "backup", warnings),
+        hosts_sources: take_list(&mut document, "hosts_sources", warnings),
+        allowed_hosts: take_list(&mut document, "allowed_hosts", warnings),
+        redirected_hosts: take_list(&mut document, "redirected_hosts", warnings),
+        blocked_hosts: take_list(&mut document, "blocked_hosts", warnings),
+        blocked_patterns: take_list(&mut document, "blocked_patterns", warnings),
+    }
+}
+
+// Puts the files used before version 1 together, returns None if there aren't any
+fn load_legacy() -> Result<Option<Document>, String> {
+    let mut document = Document::new();
+    for (file_name, key) in LEGACY_FILES {
+        let path = PROJECT_DIRS.config_dir().join(file_name);
+        if let Some(value) = atomic::load_json::<Value>(&path)
+            .map_err(|e| format!("Failed to load {}: {}", path.display(), e))?
+        {
+            document.insert(key.to_string(), value);
+        }
+    }
+    Ok((!document.is_empty()).then_some(document))
+}
+
+// Keeps a copy of the document as it was before migrating it
+fn backup_document(document: &Document, version: u64) -> Result<(), String> {
+    let dir = migrations_dir();
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let path = dir.join(format!("config-v{}-{}.json", version, timestamp));
+    atomic::write(&path, serde_json::to_string_pretty(document).unwrap())
+        .map_err(|e| format!("Failed to back up the config to {}: {}", path.display(), e))?;
+    eprintln!("Backed up the config to {}", path.display());
+    Ok(())
+}
+
+// Held while the config is read or written, so the window, the command line and the daemon
+// never lose each other's changes. Released when the file is dropped.
+fn lock() -> Result<File, String> {
+    let path = PROJECT_DIRS.config_dir().join("config.lock");
+    let lock_error = |e: std::io::Error| format!("Failed to lock {}: {}", path.display(), e);
+    fs::create_dir_all(PROJECT_DIRS.config_dir()).map_err(lock_error)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .map_err(lock_error)?;
+    // Other platforms only have one process using the config at a time, there's no daemon
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+        // SAFETY: the descriptor belongs to `file`, which outlives the call
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(lock_error(std::io::Error::last_os_error()));
+        }
+    }
+    Ok(file)
+}
+
+// Loads the config, the caller holds the lock
+fn load_locked() -> Result<(Config, Vec<String>), String> {
+    let path = config_path();
+    let mut warnings = vec![];
+    let mut document = match atomic::load_json::<Value>(&path)? {
+        Some(Value::Object(document)) => document,
+        Some(_) => return Err(format!("{} isn't a JSON object", path.display())),
+        None => match load_legacy()? {
+            Some(document) => document,
+            None => return Ok((Config::default(), warnings)),
+        },
+    };
+    let version = document.get("version").and_then(Value::as_u64).unwrap_or(0);
+    if version > CONFIG_VERSION {
+        return Err(format!(
+            "{} is from a newer version of yawada (config version {}, this one supports up to {})",
+            path.display(),
+            version,
+            CONFIG_VERSION
+        ));
+    }
+    if version == CONFIG_VERSION {
+        return Ok((from_document(document, &mut warnings), warnings));
+    }
+    for from in version..CONFIG_VERSION {
+        backup_document(&document, from)?;
+        MIGRATIONS[from as usize](&mut document);
+        document.insert("version".to_string(), Value::from(from + 1));
+        eprintln!("Migrated the config from version {} to {}", from, from + 1);
+    }
+    let config = from_document(document, &mut warnings);
+    save_locked(&config)?;
+    Ok((config, warnings))
+}
+
+// Saves the config, the caller holds the lock
+fn save_locked(config: &Config) -> Result<(), String> {
+    atomic::write_with_backup(
+        &config_path(),
+        serde_json::to_string_pretty(config).unwrap(),
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// Loads the config, migrating older layouts to the current one.
+///
+/// Besides the config, returns what couldn't be read and was dropped or reset.
+/// Every migration is preceded by a backup in [`migrations_dir`], and the
+/// migrated config is saved right away.
+pub fn load_with_warnings() -> Result<(Config, Vec<String>), String> {
+    let _lock = lock()?;
+    load_locked()
+}
+
+/// Loads the config, logging what couldn't be read.
+pub fn load() -> Result<Config, String> {
+    let (config, warnings) = load_with_warnings()?;
+    for warning in warnings {
+        eprintln!("{}", warning);
+    }
+    Ok(config)
+}
+
+/// Changes part of the config on disk, keeping everything else as it is.
+///
+/// The config stays locked from reading to writing, so concurrent changes by another
+/// process aren't lost. Nothing is written if the change leaves the config as it was.
+/// Returns what `change` returned.
+pub fn update<T>(change: impl FnOnce(&mut Config) -> T) -> Result<T, String> {
+    let _lock = lock()?;
+    let (mut config, warnings) = load_locked()?;
+    for warning in warnings {
+        eprintln!("{}", warning);
+    }
+    let before = serde_json::to_value(&config).unwrap();
+    let result = change(&mut config);
+    if serde_json::to_value(&config).unwrap() != before {
+        save_locked(&config)?;
+    }
+    Ok(result)
+}
@@ -0,0 +1,391 @@
+use crate::fetch::{self, FetchStatus};
+use crate::install::{self, InstallState};
+use crate::settings::Settings;
+use crate::{backup, config, helper, hosts, network, schedule, HostsSource, PROJECT_DIRS};
+use eframe::egui;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How long a client waits for an answer, installing may wait for an authorization prompt
+#[cfg(unix)]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+// How long the daemon waits for a client to send its request
+#[cfg(unix)]
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests accepted on the control socket, one JSON object per line.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    // Update the given sources, or every enabled one if there are none
+    UpdateNow {
+        #[serde(default)]
+        urls: Vec<String>,
+    },
+    Install,
+    Uninstall,
+    // Read the config files again
+    ReloadConfig,
+    // Replace the hosts sources file, so only the daemon ever writes it
+    SetSources {
+        sources: Vec<HostsSource>,
+    },
+}
+
+/// State of the daemon, sent back for every request.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub updating: bool,
+    // Status of every source in the running or the last update
+    pub progress: Vec<(String, FetchStatus)>,
+    // When the last update finished, 0 if there wasn't any
+    pub last_finished: u64,
+    pub hosts_file: PathBuf,
+    pub state: InstallState,
+    pub blocked_hosts: usize,
+}
+
+/// Returns the path of the control socket.
+///
+/// `YAWADA_SOCKET` wins over the runtime directory, which wins over the data directory.
+pub fn socket_path() -> PathBuf {
+    if let Some(path) = std::env::var_os("YAWADA_SOCKET").filter(|p| !p.is_empty()) {
+        return PathBuf::from(path);
+    }
+    match std::env::var_os("XDG_RUNTIME_DIR").filter(|p| !p.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join("yawada.sock"),
+        None => PROJECT_DIRS.data_dir().join("yawada.sock"),
+    }
+}
+
+/// Sends a request to the running daemon and returns its status afterwards.
+#[cfg(unix)]
+pub fn request(request: &Request) -> Result<Status, String> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+    let mut stream = UnixStream::connect(socket_path())
+        .map_err(|e| format!("Failed to connect to the daemon: {}", e))?;
+    stream
+        .set_read_timeout(Some(REQUEST_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    let mut line = serde_json::to_string(request).unwrap();
+    line.push('\n');
+    stream
+        .write_all(line.as_bytes())
+        .map_err(|e| format!("Failed to send the request to the daemon: {}", e))?;
+    let mut response = String::new();
+    BufReader::new(stream)
+        .read_line(&mut response)
+        .map_err(|e| format!("Failed to read the answer of the daemon: {}", e))?;
+    serde_json::from_str::<Result<Status, String>>(&response)
+        .map_err(|e| format!("Invalid answer from the daemon: {}", e))?
+}
+
+/// Sends a request to the running daemon and returns its status afterwards.
+#[cfg(not(unix))]
+pub fn request(_request: &Request) -> Result<Status, String> {
+    Err("The daemon is only supported on Unix".into())
+}
+
+/// Returns the status of the daemon, or `None` if it isn't running.
+pub fn status() -> Option<Status> {
+    request(&Request::Status).ok()
+}
+
+/// Tells the daemon to read the config files again, if it's running.
+pub fn reload_config(status: &mut Option<Status>) {
+    if status.is_none() {
+        return;
+    }
+    match request(&Request::ReloadConfig) {
+        Ok(new_status) => *status = Some(new_status),
+        Err(e) => eprintln!("Failed to reload the config of the daemon: {}", e),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// Everything the daemon works with, owned by its main loop
+struct Daemon {
+    settings: Settings,
+    retention: usize,
+    sources: Vec<HostsSource>,
+    blocked_hosts: usize,
+    compiled: String,
+    scheduler: schedule::Scheduler,
+    job: Option<fetch::FetchJob>,
+    progress: Vec<(String, FetchStatus)>,
+    last_finished: u64,
+}
+
+impl Daemon {
+    fn load() -> Result<Self, String> {
+        let mut daemon = Self {
+            settings: Settings::default(),
+            retention: 0,
+            sources: vec![],
+            blocked_hosts: 0,
+            compiled: String::new(),
+            scheduler: schedule::Scheduler::default(),
+            job: None,
+            progress: vec![],
+            last_finished: 0,
+        };
+        daemon.reload()?;
+        Ok(daemon)
+    }
+
+    // Reads the config files again and compiles the hosts
+    fn reload(&mut self) -> Result<(), String> {
+        let config = config::load().map_err(|e| format!("Failed to load the config: {}", e))?;
+        self.settings = config.settings;
+        network::configure(&self.settings)?;
+        self.retention = config.backup.retention;
+        self.sources = config.hosts_sources;
+        let (blocked_hosts, _) = hosts::load_blocked_hosts(
+            &self.sources,
+            &config.blocked_hosts,
+            &config.blocked_patterns,
+        );
+        self.blocked_hosts = blocked_hosts.len();
+        self.compiled = install::compile_hosts(
+            &blocked_hosts,
+            &config.allowed_hosts,
+            &config.redirected_hosts,
+            &self.settings,
+        );
+        Ok(())
+    }
+
+    fn status(&self) -> Status {
+        let hosts_file = install::hosts_path(&self.settings);
+        Status {
+            updating: self.job.is_some(),
+            progress: match &self.job {
+                Some(job) => job.progress(),
+                None => self.progress.clone(),
+            },
+            last_finished: self.last_finished,
+            state: install::read_install_state(&hosts_file, &self.compiled),
+            hosts_file,
+            blocked_hosts: self.blocked_hosts,
+        }
+    }
+
+    fn start_update(&mut self, sources: Vec<HostsSource>) {
+        println!("Updating {} hosts sources", sources.len());
+        self.job = Some(fetch::FetchJob::start(
+            &egui::Context::default(),
+            sources,
+            network::RetryPolicy::from_settings(&self.settings),
+        ));
+    }
+
+    fn handle(&mut self, request: Request) -> Result<Status, String> {
+        match request {
+            Request::Status => {}
+            Request::UpdateNow { urls } => {
+                if self.job.is_some() {
+                    return Err("An update is already running".into());
+                }
+                let sources = if urls.is_empty() {
+                    self.sources.iter().filter(|s| s.enabled).cloned().collect()
+                } else {
+                    let mut sources = vec![];
+                    for url in urls.iter() {
+                        match self.sources.iter().find(|s| &s.url == url) {
+                            Some(source) => sources.push(source.clone()),
+                            None => return Err(format!("Unknown source: {}", url)),
+                        }
+                    }
+                    sources
+                };
+                self.start_update(sources);
+            }
+            Request::Install => {
+                let hosts_path = install::hosts_path(&self.settings);
+                backup::snapshot_and_prune(&hosts_path, self.retention)?;
+                helper::perform(&helper::Request::install(hosts_path, self.compiled.clone()))?;
+                println!("Installed hosts file");
+            }
+            Request::Uninstall => {
+                let hosts_path = install::hosts_path(&self.settings);
+                backup::snapshot_and_prune(&hosts_path, self.retention)?;
+                helper::perform(&helper::Request::uninstall(hosts_path))?;
+                println!("Uninstalled hosts file");
+            }
+            Request::ReloadConfig => {
+                self.reload()?;
+                println!("Reloaded config");
+            }
+            Request::SetSources { sources } => {
+                let mut urls: Vec<&str> = vec![];
+                for source in sources.iter() {
+                    if source.url.is_empty() {
+                        return Err("The host URL cannot be empty".into());
+                    }
+                    if urls.contains(&source.url.as_str()) {
+                        return Err(format!("Duplicate hosts source: {}", source.url));
+                    }
+                    urls.push(&source.url);
+                }
+                config::update(|config| config.hosts_sources = sources)
+                    .map_err(|e| format!("Failed to save hosts sources: {}", e))?;
+                self.reload()?;
+                println!("Saved hosts sources");
+            }
+        }
+        Ok(self.status())
+    }
+
+    // Collects a finished update and starts the scheduled ones
+    fn tick(&mut self) {
+        if let Some(results) = self.job.as_mut().and_then(|job| job.poll_finished()) {
+            self.job = None;
+            print!("{}", fetch::summary(&results));
+            if let Err(e) = schedule::record_results(&results) {
+                eprintln!("Failed to save hosts sources: {}", e);
+            }
+            if let Err(e) = self.reload() {
+                eprintln!("Failed to reload config: {}", e);
+            }
+            let updated = results
+                .iter()
+                .any(|(_, status)| matches!(status, FetchStatus::Done { .. }));
+            // Only installed on its own if the user asked for it, like in the window
+            if updated && self.settings.auto_install {
+                match schedule::reinstall_if_stale(
+                    &self.settings,
+                    self.retention,
+                    self.compiled.clone(),
+                ) {
+                    Ok(true) => println!("Installed hosts file"),
+                    Ok(false) => {}
+                    Err(e) => eprintln!("Failed to install hosts file: {}", e),
+                }
+            }
+            self.progress = results;
+            self.last_finished = now();
+        }
+        if self.job.is_none() {
+            let due = self.scheduler.poll(&self.sources, &self.settings);
+            if !due.is_empty() {
+                self.start_update(due);
+            }
+        }
+    }
+}
+
+/// Runs the daemon until the process is killed, returns the exit code if it can't start.
+///
+/// The daemon updates the sources on schedule, installs the hosts file again when an
+/// update changes it and automatic installs are on, and answers requests on the control socket.
+pub fn run() -> i32 {
+    let mut daemon = match Daemon::load() {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let requests = match listen() {
+        Ok(requests) => requests,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    loop {
+        match requests.recv_timeout(Duration::from_secs(1)) {
+            Ok((request, reply)) => {
+                let _ = reply.send(daemon.handle(request));
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return 1,
+        }
+        daemon.tick();
+    }
+}
+
+// A request and where to send its answer
+type Message = (Request, mpsc::Sender<Result<Status, String>>);
+
+// Accepts requests on the control socket and forwards them to the main loop,
+// so the config is only ever touched from there
+#[cfg(unix)]
+fn listen() -> Result<mpsc::Receiver<Message>, String> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    let path = socket_path();
+    if status().is_some() {
+        return Err(format!(
+            "Another daemon is already listening on {}",
+            path.display()
+        ));
+    }
+    // Left behind by a daemon that didn't exit cleanly
+    let _ = std::fs::remove_file(&path);
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let listener = UnixListener::bind(&path)
+        .map_err(|e| format!("Failed to listen on {}: {}", path.display(), e))?;
+    // Only the user running the daemon may control it
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("Failed to restrict {}: {}", path.display(), e))?;
+    println!("Listening on {}", path.display());
+    let (sender, receiver) = mpsc::channel::<Message>();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+            };
+            let _ = stream.set_read_timeout(Some(CLIENT_TIMEOUT));
+            let mut line = String::new();
+            let response = match BufReader::new(&stream).read_line(&mut line) {
+                Ok(_) => match serde_json::from_str::<Request>(&line) {
+                    Ok(request) => {
+                        let (reply, answer) = mpsc::channel();
+                        if sender.send((request, reply)).is_err() {
+                            break;
+                        }
+                        answer
+                            .recv()
+                            .unwrap_or_else(|_| Err("The daemon stopped".into()))
+                    }
+                    Err(e) => Err(format!("Invalid request: {}", e)),
+                },
+                Err(e) => Err(format!("Failed to read the request: {}", e)),
+            };
+            let mut line = serde_json::to_string(&response).unwrap();
+            line.push('\n');
+            let _ = stream.write_all(line.as_bytes());
+        }
+    });
+    Ok(receiver)
+}
+
+// There is no control socket, the daemon only runs the schedule
+#[cfg(not(unix))]
+fn listen() -> Result<mpsc::Receiver<Message>, String> {
+    println!("The control socket is only supported on Unix, running the schedule only");
+    let (sender, receiver) = mpsc::channel::<Message>();
+    // Keep the channel open so the main loop doesn't stop
+    std::mem::forget(sender);
+    Ok(receiver)
+}
@@ -0,0 +1,470 @@
+use crate::{atomic, hosts, network, schedule, validate, verify, HostsSource};
+use eframe::egui;
+use reqwest::header::{CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How many servers are downloaded from at the same time
+const MAX_CONCURRENT_FETCHES: usize = 4;
+// Pause between two requests to the same server
+const POLITENESS_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "status", content = "detail", rename_all = "snake_case")]
+pub enum FetchStatus {
+    Queued,
+    Downloading { bytes: u64, total: Option<u64> },
+    Done { bytes: u64, fetched_at: u64 },
+    // The server says the source wasn't modified, or the content is the same as the cached copy
+    Unchanged { checked_at: u64 },
+    // The content didn't pass validation, the cached copy was kept
+    Rejected(String),
+    Failed(String),
+    Cancelled,
+}
+
+impl FetchStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            FetchStatus::Done { .. }
+                | FetchStatus::Unchanged { .. }
+                | FetchStatus::Rejected(_)
+                | FetchStatus::Failed(_)
+                | FetchStatus::Cancelled
+        )
+    }
+}
+
+impl fmt::Display for FetchStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchStatus::Queued => write!(f, "Queued"),
+            FetchStatus::Downloading { bytes, total } => match total {
+                Some(total) => write!(f, "Downloading ({} / {} bytes)", bytes, total),
+                None => write!(f, "Downloading ({} bytes)", bytes),
+            },
+            FetchStatus::Done { bytes, .. } => write!(f, "Done ({} bytes)", bytes),
+            FetchStatus::Unchanged { .. } => write!(f, "Unchanged"),
+            FetchStatus::Rejected(e) => write!(f, "Rejected: {}", e),
+            FetchStatus::Failed(e) => write!(f, "Failed: {}", e),
+            FetchStatus::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+
+/// Downloads hosts sources into the cache on background threads.
+pub struct FetchJob {
+    urls: Vec<String>,
+    statuses: Arc<Mutex<Vec<FetchStatus>>>,
+    cancelled: Arc<AtomicBool>,
+    // Whether the results were handed out by `poll_finished`
+    collected: bool,
+}
+
+impl FetchJob {
+    /// Starts downloading the given sources, repainting `ctx` whenever there is progress.
+    ///
+    /// Sources on the same server are downloaded one after another, with a pause in between.
+    pub fn start(
+        ctx: &egui::Context,
+        sources: Vec<HostsSource>,
+        policy: network::RetryPolicy,
+    ) -> Self {
+        let urls: Vec<String> = sources.iter().map(|s| s.url.clone()).collect();
+        let statuses = Arc::new(Mutex::new(vec![FetchStatus::Queued; urls.len()]));
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let index = Arc::new(Mutex::new(hosts::load_cache_index()));
+        // Indices of the sources, grouped by server
+        let mut groups: Vec<(String, Vec<usize>)> = vec![];
+        for (i, url) in urls.iter().enumerate() {
+            let key = server(url).unwrap_or_else(|| format!("#{}", i));
+            match groups.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, group)) => group.push(i),
+                None => groups.push((key, vec![i])),
+            }
+        }
+        let groups = Arc::new(groups);
+        let next = Arc::new(AtomicUsize::new(0));
+        for _ in 0..MAX_CONCURRENT_FETCHES.min(groups.len()) {
+            let ctx = ctx.clone();
+            let sources = sources.clone();
+            let groups = groups.clone();
+            let statuses = statuses.clone();
+            let cancelled = cancelled.clone();
+            let index = index.clone();
+            let next = next.clone();
+            thread::spawn(move || loop {
+                let group = match groups.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    Some((_, group)) => group,
+                    None => break,
+                };
+                for (n, &i) in group.iter().enumerate() {
+                    if n > 0 && !cancelled.load(Ordering::SeqCst) {
+                        thread::sleep(POLITENESS_DELAY);
+                    }
+                    let set_status = |status: FetchStatus| {
+                        statuses.lock().unwrap()[i] = status;
+                        ctx.request_repaint();
+                    };
+                    let url = &sources[i].url;
+                    let status = fetch(&sources[i], policy, &index, &cancelled, &set_status);
+                    match &status {
+                        FetchStatus::Failed(e) => {
+                            eprintln!("Failed to fetch hosts source {}: {}", url, e)
+                        }
+                        FetchStatus::Rejected(e) => {
+                            eprintln!("Rejected hosts source {}: {}", url, e)
+                        }
+                        _ => {}
+                    }
+                    set_status(status);
+                }
+            });
+        }
+        Self {
+            urls,
+            statuses,
+            cancelled,
+            collected: false,
+        }
+    }
+
+    /// Stops the job, downloads in progress are discarded.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.statuses
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|s| s.is_finished())
+    }
+
+    /// Returns the current status of every source in the job.
+    pub fn progress(&self) -> Vec<(String, FetchStatus)> {
+        let statuses = self.statuses.lock().unwrap();
+        self.urls
+            .iter()
+            .cloned()
+            .zip(statuses.iter().cloned())
+            .collect()
+    }
+
+    /// Blocks until every source is finished and returns the final statuses.
+    pub fn wait(mut self) -> Vec<(String, FetchStatus)> {
+        loop {
+            if let Some(results) = self.poll_finished() {
+                return results;
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
+    }
+
+    /// Returns the final statuses once, after every source is finished.
+    pub fn poll_finished(&mut self) -> Option<Vec<(String, FetchStatus)>> {
+        if self.collected || !self.is_finished() {
+            return None;
+        }
+        self.collected = true;
+        Some(self.progress())
+    }
+}
+
+/// Describes the outcome of a finished job, one line per source.
+pub fn summary(results: &[(String, FetchStatus)]) -> String {
+    let mut updated = vec![];
+    let mut unchanged = vec![];
+    let mut rejected = vec![];
+    let mut failed = vec![];
+    let mut cancelled = vec![];
+    for (url, status) in results {
+        match status {
+            FetchStatus::Done { .. } => updated.push(url.clone()),
+            FetchStatus::Unchanged { .. } => unchanged.push(url.clone()),
+            FetchStatus::Rejected(e) => rejected.push(format!("{}: {}", url, e)),
+            FetchStatus::Failed(e) => failed.push(format!("{}: {}", url, e)),
+            FetchStatus::Cancelled => cancelled.push(url.clone()),
+            FetchStatus::Queued | FetchStatus::Downloading { .. } => {}
+        }
+    }
+    let mut summary = String::new();
+    for (title, lines) in [
+        ("Updated", updated),
+        ("Unchanged", unchanged),
+        ("Rejected, kept the previous version", rejected),
+        ("Failed", failed),
+        ("Cancelled", cancelled),
+    ] {
+        if lines.is_empty() {
+            continue;
+        }
+        summary.push_str(&format!("{} ({}):\n", title, lines.len()));
+        for line in lines {
+            summary.push_str(&format!("  {}\n", line));
+        }
+    }
+    if summary.is_empty() {
+        summary.push_str("There are no enabled sources to update.");
+    }
+    summary
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// Stores the metadata of a source in the shared index and saves it
+fn update_index(index: &Mutex<hosts::CacheIndex>, url: &str, meta: hosts::CacheMeta) {
+    let mut index = index.lock().unwrap();
+    index.insert(url.to_string(), meta);
+    if let Err(e) = hosts::save_cache_index(&index) {
+        eprintln!("Failed to save cache index: {}", e);
+    }
+}
+
+// Flags a source in the index, keeping the rest of its metadata
+fn reject(index: &Mutex<hosts::CacheIndex>, url: &str, reason: String) -> FetchStatus {
+    let mut meta = index.lock().unwrap().get(url).cloned().unwrap_or_default();
+    meta.rejected = Some(reason.clone());
+    update_index(index, url, meta);
+    FetchStatus::Rejected(reason)
+}
+
+// Returns the server of a downloaded source
+fn server(url: &str) -> Option<String> {
+    match hosts::location(url) {
+        hosts::Location::Http => reqwest::Url::parse(url)
+            .ok()?
+            .host_str()
+            .map(|h| h.to_ascii_lowercase()),
+        _ => None,
+    }
+}
+
+// Content of a source and the metadata used to detect changes
+struct Body {
+    content: Vec<u8>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    modified: Option<u64>,
+}
+
+// Downloads a source over HTTP
+//
+// The cached ETag and Last-Modified are sent along, so servers can skip
+// sending a list we already have. `None` means the server says it's unchanged.
+fn download(
+    url: &str,
+    policy: network::RetryPolicy,
+    cached_meta: Option<&hosts::CacheMeta>,
+    index: &Mutex<hosts::CacheIndex>,
+    cancelled: &AtomicBool,
+    set_status: &dyn Fn(FetchStatus),
+) -> Result<Option<Body>, FetchStatus> {
+    let mut request = network::client().get(url);
+    if let Some(meta) = cached_meta {
+        if let Some(etag) = &meta.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &meta.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let mut response = match network::send_with_retry(request, policy, cancelled) {
+        Ok(response) => response,
+        Err(_) if cancelled.load(Ordering::SeqCst) => return Err(FetchStatus::Cancelled),
+        Err(e) => return Err(FetchStatus::Failed(e)),
+    };
+    if response.status() == StatusCode::NOT_MODIFIED && cached_meta.is_some() {
+        return Ok(None);
+    }
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    let etag = header(ETAG);
+    let last_modified = header(LAST_MODIFIED);
+    if let Err(e) = validate::validate_response(response.status(), header(CONTENT_TYPE).as_deref())
+    {
+        return Err(reject(index, url, e));
+    }
+    let total = response.content_length();
+    let too_large = || {
+        format!(
+            "The list is larger than the limit of {} MiB",
+            validate::MAX_BODY_BYTES / 1024 / 1024
+        )
+    };
+    if total.is_some_and(|total| total > validate::MAX_BODY_BYTES) {
+        return Err(reject(index, url, too_large()));
+    }
+    let mut content = vec![];
+    let mut buf = [0; 16 * 1024];
+    loop {
+        if cancelled.load(Ordering::SeqCst) {
+            return Err(FetchStatus::Cancelled);
+        }
+        let n = response
+            .read(&mut buf)
+            .map_err(|e| FetchStatus::Failed(e.to_string()))?;
+        if n == 0 {
+            break;
+        }
+        // The length header may be missing or wrong, so the body is checked as it comes in
+        if content.len() as u64 + n as u64 > validate::MAX_BODY_BYTES {
+            return Err(reject(index, url, too_large()));
+        }
+        content.extend_from_slice(&buf[..n]);
+        set_status(FetchStatus::Downloading {
+            bytes: content.len() as u64,
+            total,
+        });
+    }
+    Ok(Some(Body {
+        content,
+        etag,
+        last_modified,
+        modified: None,
+    }))
+}
+
+// Reads a local source, `None` means its modification time didn't change
+fn read_file(path: &Path, cached_meta: Option<&hosts::CacheMeta>) -> Result<Option<Body>, String> {
+    let modified = fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .ok();
+    if modified.is_some() && cached_meta.is_some_and(|m| m.modified == modified) {
+        return Ok(None);
+    }
+    let content =
+        fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    Ok(Some(Body {
+        content,
+        etag: None,
+        last_modified: None,
+        modified,
+    }))
+}
+
+// Gets the content of a source and writes it to the cache
+fn fetch(
+    source: &HostsSource,
+    policy: network::RetryPolicy,
+    index: &Mutex<hosts::CacheIndex>,
+    cancelled: &AtomicBool,
+    set_status: &dyn Fn(FetchStatus),
+) -> FetchStatus {
+    let url = source.url.as_str();
+    if cancelled.load(Ordering::SeqCst) {
+        return FetchStatus::Cancelled;
+    }
+    set_status(FetchStatus::Downloading {
+        bytes: 0,
+        total: None,
+    });
+    let cache_path = hosts::cache_path(url);
+    // Without a cached copy a "not modified" would leave us with nothing
+    let cached_meta = if cache_path.exists() {
+        index.lock().unwrap().get(url).cloned()
+    } else {
+        None
+    };
+    let body = match hosts::location(url) {
+        hosts::Location::Http => download(
+            url,
+            policy,
+            cached_meta.as_ref(),
+            index,
+            cancelled,
+            set_status,
+        ),
+        hosts::Location::File(path) => {
+            read_file(&path, cached_meta.as_ref()).map_err(FetchStatus::Failed)
+        }
+        hosts::Location::Inline => Ok(Some(Body {
+            content: source.content.clone().into_bytes(),
+            etag: None,
+            last_modified: None,
+            modified: None,
+        })),
+    };
+    let body = match body {
+        Ok(Some(body)) => body,
+        Ok(None) => return FetchStatus::Unchanged { checked_at: now() },
+        Err(status) => return status,
+    };
+    let entries = match validate::validate_content(
+        &body.content,
+        source.format,
+        cached_meta.as_ref().map(|m| m.entries),
+        matches!(hosts::location(url), hosts::Location::Http),
+    ) {
+        Ok(entries) => entries,
+        Err(e) => return reject(index, url, e),
+    };
+    let verified = match verify::verify(source, &body.content) {
+        Ok(verified) => verified,
+        Err(e) => return reject(index, url, e),
+    };
+    let digest = blake3::hash(&body.content).to_hex().to_string();
+    let fetched_at = now();
+    let unchanged = match &cached_meta {
+        Some(meta) => meta.blake3 == digest,
+        // The index may be missing, compare with the cached copy itself
+        None => fs::read(&cache_path)
+            .is_ok_and(|cached| blake3::hash(&cached).to_hex().as_str() == digest),
+    };
+    if !unchanged {
+        if let Err(e) = atomic::write_with_backup(&cache_path, &body.content) {
+            return FetchStatus::Failed(e.to_string());
+        }
+    }
+    update_index(
+        index,
+        url,
+        hosts::CacheMeta {
+            etag: body.etag,
+            last_modified: body.last_modified,
+            modified: body.modified,
+            content_length: body.content.len() as u64,
+            blake3: digest,
+            fetched_at: match (&cached_meta, unchanged) {
+                (Some(meta), true) => meta.fetched_at,
+                _ => fetched_at,
+            },
+            entries,
+            verified,
+            rejected: None,
+            expires: schedule::parse_expires(&body.content),
+        },
+    );
+    if unchanged {
+        return FetchStatus::Unchanged {
+            checked_at: fetched_at,
+        };
+    }
+    FetchStatus::Done {
+        bytes: body.content.len() as u64,
+        fetched_at,
+    }
+}
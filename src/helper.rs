@@ -0,0 +1,283 @@
+use crate::{atomic, hosts, install};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+// The biggest request the helper accepts, hosts files are never this large
+const MAX_REQUEST_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A request to the privileged helper.
+///
+/// The helper reads exactly one request as JSON from stdin, validates it,
+/// performs it and reports failures on stderr with a non-zero exit code.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    // Write `content` (the compiled hosts) into the managed section
+    Install {
+        path: PathBuf,
+        content: String,
+        digest: String,
+    },
+    // Remove the managed section
+    Uninstall {
+        path: PathBuf,
+    },
+    // Replace the whole file with `content` (a snapshot)
+    Restore {
+        path: PathBuf,
+        content: String,
+        digest: String,
+    },
+}
+
+impl Request {
+    pub fn install(path: PathBuf, content: String) -> Self {
+        let digest = blake3::hash(content.as_bytes()).to_hex().to_string();
+        Request::Install {
+            path,
+            content,
+            digest,
+        }
+    }
+
+    pub fn uninstall(path: PathBuf) -> Self {
+        Request::Uninstall { path }
+    }
+
+    pub fn restore(path: PathBuf, content: String) -> Self {
+        let digest = blake3::hash(content.as_bytes()).to_hex().to_string();
+        Request::Restore {
+            path,
+            content,
+            digest,
+        }
+    }
+
+    fn path(&self) -> &Path {
+        match self {
+            Request::Install { path, .. }
+            | Request::Uninstall { path }
+            | Request::Restore { path, .. } => path,
+        }
+    }
+}
+
+// Root-owned list of hosts files the helper may write besides the system one,
+// one absolute path per line, e.g. the hosts file of a container
+pub const ALLOWED_PATHS_FILE: &str = "/etc/yawada/helper-paths";
+
+// Returns the files the helper may write. The request comes from an unprivileged user,
+// so only the system hosts file and what root allowed in ALLOWED_PATHS_FILE are accepted.
+fn allowed_paths() -> Vec<PathBuf> {
+    let mut paths = vec![install::system_hosts_path()];
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match fs::symlink_metadata(ALLOWED_PATHS_FILE) {
+            // Nobody but root may be able to change the list
+            Ok(metadata)
+                if metadata.is_file() && metadata.uid() == 0 && metadata.mode() & 0o022 == 0 =>
+            {
+                let content = fs::read_to_string(ALLOWED_PATHS_FILE).unwrap_or_default();
+                paths.extend(
+                    content
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                        .map(PathBuf::from)
+                        .filter(|path| path.is_absolute()),
+                );
+            }
+            Ok(_) => eprintln!(
+                "Ignoring {}, it must be a file only root can write",
+                ALLOWED_PATHS_FILE
+            ),
+            Err(_) => {}
+        }
+    }
+    paths
+}
+
+// Checks the target is a hosts file the helper is allowed to write
+fn validate_path(path: &Path) -> Result<(), String> {
+    if !allowed_paths().iter().any(|allowed| allowed == path) {
+        return Err(format!(
+            "{} is not a hosts file the helper may write, list it in {} to allow it",
+            path.display(),
+            ALLOWED_PATHS_FILE
+        ));
+    }
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => Err(format!(
+            "Refusing to write through symlink {}",
+            path.display()
+        )),
+        Ok(metadata) if !metadata.is_file() => Err(format!("{} is not a file", path.display())),
+        #[cfg(unix)]
+        Ok(metadata) if std::os::unix::fs::MetadataExt::nlink(&metadata) > 1 => Err(format!(
+            "Refusing to write {}, it has other hard links",
+            path.display()
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn validate_digest(content: &str, digest: &str) -> Result<(), String> {
+    if blake3::hash(content.as_bytes()).to_hex().as_str() != digest {
+        return Err("The content doesn't match its digest".into());
+    }
+    Ok(())
+}
+
+// Every line must map an IP address to hostnames, comments are only allowed
+// if `allow_comments` is set
+fn validate_content(content: &str, allow_comments: bool) -> Result<(), String> {
+    for (i, line) in content.lines().enumerate() {
+        let entry = match line.find('#') {
+            Some(_) if !allow_comments => {
+                return Err(format!("Line {} contains a comment: {}", i + 1, line))
+            }
+            Some(pos) => &line[..pos],
+            None => line,
+        };
+        let mut tokens = entry.split_whitespace();
+        let ip = match tokens.next() {
+            Some(ip) => ip,
+            None if allow_comments => continue,
+            None => return Err(format!("Line {} is empty", i + 1)),
+        };
+        let hosts: Vec<&str> = tokens.collect();
+        if ip.parse::<IpAddr>().is_err()
+            || hosts.is_empty()
+            || !hosts.iter().all(|h| hosts::is_valid_hostname(h))
+        {
+            return Err(format!(
+                "Line {} is not a valid hosts entry: {}",
+                i + 1,
+                line
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Validates and performs a request in the current process.
+///
+/// The target path isn't restricted here, we only write files the user can write
+/// anyway. The privileged helper checks it in [`run_helper`].
+pub fn execute(request: &Request) -> Result<(), String> {
+    match request {
+        Request::Install {
+            path,
+            content,
+            digest,
+        } => {
+            validate_digest(content, digest)?;
+            validate_content(content, false)?;
+            install::install(path, content)
+        }
+        Request::Uninstall { path } => install::uninstall(path),
+        Request::Restore {
+            path,
+            content,
+            digest,
+        } => {
+            validate_digest(content, digest)?;
+            validate_content(content, true)?;
+            atomic::rewrite_with_backup(path, content)
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+        }
+    }
+}
+
+/// Entry point of the privileged helper, returns the exit code.
+pub fn run_helper() -> i32 {
+    let mut input = String::new();
+    if let Err(e) = std::io::stdin()
+        .take(MAX_REQUEST_SIZE)
+        .read_to_string(&mut input)
+    {
+        eprintln!("Failed to read request: {}", e);
+        return 2;
+    }
+    let request: Request = match serde_json::from_str(&input) {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("Invalid request: {}", e);
+            return 2;
+        }
+    };
+    match validate_path(request.path()).and_then(|_| execute(&request)) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+// Checks if we can write to the file without extra privileges
+fn can_write(path: &Path) -> bool {
+    match fs::OpenOptions::new().append(true).open(path) {
+        Ok(_) => true,
+        Err(e) => e.kind() == ErrorKind::NotFound,
+    }
+}
+
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+// Runs the request through a copy of ourselves started with pkexec or sudo
+fn spawn_helper(request: &Request) -> Result<(), String> {
+    if !cfg!(target_os = "linux") {
+        return Err(format!(
+            "Writing {} requires administrator privileges, please run yawada as administrator",
+            request.path().display()
+        ));
+    }
+    let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+    let mut command = match find_in_path("pkexec") {
+        Some(pkexec) => Command::new(pkexec),
+        None => Command::new("sudo"),
+    };
+    let mut child = command
+        .arg(exe)
+        .arg("helper")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to start the privileged helper: {}", e))?;
+    // Dropping stdin closes it so the helper stops reading
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(&serde_json::to_vec(request).unwrap())
+        .map_err(|e| format!("Failed to send the request to the helper: {}", e))?;
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Failed to wait for the privileged helper: {}", e))?;
+    match output.status.code() {
+        Some(0) => Ok(()),
+        // pkexec uses these when the authentication is dismissed or fails
+        Some(126) | Some(127) => Err("Authorization was cancelled or denied".into()),
+        _ => Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+    }
+}
+
+/// Performs a request, going through the privileged helper only if we can't write the file ourselves.
+pub fn perform(request: &Request) -> Result<(), String> {
+    if can_write(request.path()) {
+        execute(request)
+    } else {
+        spawn_helper(request)
+    }
+}
@@ -0,0 +1,585 @@
+use crate::rules::{self, MatchMode};
+use crate::{atomic, BlockedHost, BlockedPattern, HostsSource, PROJECT_DIRS};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+// Prefix of sources whose content is edited in yawada itself
+pub const INLINE_PREFIX: &str = "inline:";
+
+// Addresses that mean "this host goes nowhere" in a hosts file
+const SINK_ADDRESSES: [&str; 6] = ["0.0.0.0", "127.0.0.1", "::", "::1", "0", "::0"];
+
+// Hostnames that every hosts file has, these are not blocked hosts
+const LOCAL_HOSTNAMES: [&str; 10] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+];
+
+// Parts of a rule that make it a cosmetic or HTML filtering rule in Adblock syntax
+const COSMETIC_MARKERS: [&str; 10] = [
+    "##", "#@#", "#?#", "#@?#", "#$#", "#@$#", "#%#", "#@%#", "$$", "$@$",
+];
+
+// Rule options that still block the whole domain, anything else narrows the rule down
+const DOMAIN_OPTIONS: [&str; 4] = ["important", "all", "document", "doc"];
+
+// Only this many lines are looked at to detect the format of a list
+const DETECT_LINES: usize = 1000;
+
+/// Syntax the content of a source is written in.
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceFormat {
+    // Detected from the content
+    #[default]
+    Auto,
+    Hosts,
+    // Adblock Plus and AdGuard filter lists
+    Adblock,
+}
+
+#[derive(Clone, Default)]
+pub struct SourceStats {
+    pub hosts: usize,
+    pub rejected_lines: usize,
+    // Exception rules of the source, merged into the allowed hosts
+    pub exceptions: usize,
+    // Cosmetic and path rules, which can't be expressed in a hosts file
+    pub ignored_rules: usize,
+    // Why the last update of the source was rejected
+    pub rejected_update: Option<String>,
+    pub verified: bool,
+}
+
+pub struct ParsedHosts {
+    pub hosts: Vec<String>,
+    // Hosts the exception rules of the source allow
+    pub allowed: Vec<String>,
+    // Lines that aren't empty or comments
+    pub lines: usize,
+    pub rejected_lines: usize,
+    // Valid rules we can't use, e.g. cosmetic or path rules
+    pub ignored_rules: usize,
+}
+
+// What we know about the cached copy of a source
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheMeta {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    // Modification time of a local source
+    pub modified: Option<u64>,
+    pub content_length: u64,
+    pub blake3: String,
+    // When the content was last downloaded
+    pub fetched_at: u64,
+    // Number of hosts entries in the cached copy
+    pub entries: usize,
+    // Whether the cached copy passed the pinned checksum or signature of the source
+    pub verified: bool,
+    // Why the last downloaded version was rejected, the cached copy is older in that case
+    pub rejected: Option<String>,
+    // Update interval the list asks for in its Expires header, in seconds
+    pub expires: Option<u64>,
+}
+
+// Cache metadata keyed by source URL
+pub type CacheIndex = HashMap<String, CacheMeta>;
+
+/// Returns the path of the cache metadata index.
+pub fn cache_index_path() -> PathBuf {
+    PROJECT_DIRS
+        .config_dir()
+        .join("hosts_sources")
+        .join("index.json")
+}
+
+/// Loads the cache metadata index, an unreadable index is treated as empty.
+pub fn load_cache_index() -> CacheIndex {
+    atomic::load_json(&cache_index_path())
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to load cache index: {}", e);
+            None
+        })
+        .unwrap_or_default()
+}
+
+/// Saves the cache metadata index.
+pub fn save_cache_index(index: &CacheIndex) -> Result<(), String> {
+    atomic::write_with_backup(&cache_index_path(), serde_json::to_string(index).unwrap())
+        .map_err(|e| e.to_string())
+}
+
+// Where the content of a source comes from
+pub enum Location {
+    Http,
+    File(PathBuf),
+    Inline,
+}
+
+/// Works out where a source lives from its URL.
+///
+/// `http://` and `https://` URLs are downloaded, `inline:<name>` sources are edited in the app,
+/// `file://` URLs and anything else are treated as local paths.
+pub fn location(url: &str) -> Location {
+    if url.starts_with(INLINE_PREFIX) {
+        return Location::Inline;
+    }
+    let lowercase = url.to_ascii_lowercase();
+    if lowercase.starts_with("http://") || lowercase.starts_with("https://") {
+        return Location::Http;
+    }
+    match url.strip_prefix("file://") {
+        // file:///C:/hosts on Windows
+        Some(path) if cfg!(windows) && path.get(2..3) == Some(":") => {
+            Location::File(PathBuf::from(&path[1..]))
+        }
+        Some(path) => Location::File(PathBuf::from(path)),
+        None => Location::File(PathBuf::from(url)),
+    }
+}
+
+/// Returns the path of the cached copy of a hosts source.
+pub fn cache_path(url: &str) -> PathBuf {
+    let file_name = blake3::hash(url.as_bytes()).to_hex().to_string();
+    PROJECT_DIRS
+        .config_dir()
+        .join("hosts_sources")
+        .join(file_name)
+}
+
+/// Checks if the given string is a hostname we can put into a hosts file.
+pub fn is_valid_hostname(host: &str) -> bool {
+    if host.is_empty() || host.len() > 253 || host.parse::<IpAddr>().is_ok() {
+        return false;
+    }
+    host.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+/// Parses a hosts-format file into a list of blocked hostnames.
+///
+/// Both `<sink> host [host...]` lines and bare `host` lines are accepted, a bare host needs a dot.
+/// Anything else (including lines pointing to a real IP) is counted as rejected.
+pub fn parse_hosts(content: &str) -> ParsedHosts {
+    let mut hosts = vec![];
+    let mut lines = 0;
+    let mut rejected_lines = 0;
+    // lines() also takes care of CRLF line endings
+    for line in content.lines() {
+        let line = match line.find('#') {
+            Some(i) => &line[..i],
+            None => line,
+        };
+        let mut tokens = line.split_whitespace().peekable();
+        let first = match tokens.peek() {
+            Some(first) => *first,
+            None => continue,
+        };
+        lines += 1;
+        if SINK_ADDRESSES.contains(&first) {
+            tokens.next();
+        } else if first.parse::<IpAddr>().is_ok() {
+            rejected_lines += 1;
+            continue;
+        } else if line.split_whitespace().count() > 1 || !first.trim_end_matches('.').contains('.')
+        {
+            // A bare line holds a single domain, anything else is text like an error page
+            rejected_lines += 1;
+            continue;
+        }
+        let mut accepted = false;
+        for token in tokens {
+            let host = token.trim_end_matches('.').to_ascii_lowercase();
+            if LOCAL_HOSTNAMES.contains(&host.as_str()) {
+                // Not junk, just not something to block
+                accepted = true;
+                continue;
+            }
+            if is_valid_hostname(&host) {
+                hosts.push(host);
+                accepted = true;
+            }
+        }
+        if !accepted {
+            rejected_lines += 1;
+        }
+    }
+    ParsedHosts {
+        hosts,
+        allowed: vec![],
+        lines,
+        rejected_lines,
+        ignored_rules: 0,
+    }
+}
+
+/// Parses an Adblock Plus or AdGuard filter list.
+///
+/// Only domain-level rules (`||example.com^`) and their exceptions (`@@||example.com^`) are used.
+/// A hosts file can't block subdomains, so a rule only blocks the domain it names.
+/// Cosmetic rules, path rules and rules with options that narrow them down are counted as ignored.
+pub fn parse_adblock(content: &str) -> ParsedHosts {
+    let mut hosts = vec![];
+    let mut allowed = vec![];
+    let mut lines = 0;
+    let mut rejected_lines = 0;
+    let mut ignored_rules = 0;
+    for line in content.lines() {
+        let line = line.trim();
+        // "[Adblock Plus 2.0]" headers and comments
+        if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+            continue;
+        }
+        if COSMETIC_MARKERS.iter().any(|marker| line.contains(marker)) {
+            lines += 1;
+            ignored_rules += 1;
+            continue;
+        }
+        // Hosts-style comments, which AdGuard accepts too
+        if line.starts_with('#') {
+            continue;
+        }
+        lines += 1;
+        let (rule, exception) = match line.strip_prefix("@@") {
+            Some(rule) => (rule, true),
+            None => (line, false),
+        };
+        let (pattern, options) = match rule.split_once('$') {
+            Some((pattern, options)) => (pattern, Some(options)),
+            None => (rule, None),
+        };
+        if let Some(options) = options {
+            if !options
+                .split(',')
+                .all(|option| DOMAIN_OPTIONS.contains(&option.trim()))
+            {
+                ignored_rules += 1;
+                continue;
+            }
+        }
+        let domain = match pattern.strip_prefix("||") {
+            Some(domain) => domain,
+            // Plain patterns match anywhere in the URL and /regex/ rules can't be mapped to a domain
+            None => {
+                ignored_rules += 1;
+                continue;
+            }
+        };
+        let domain = domain
+            .strip_suffix("^|")
+            .or_else(|| domain.strip_suffix('^'))
+            .unwrap_or(domain);
+        // Path rules and wildcards
+        if domain.contains(['/', '*', '^', '|', '?', '=', ':']) {
+            ignored_rules += 1;
+            continue;
+        }
+        let host = domain.trim_end_matches('.').to_ascii_lowercase();
+        if !is_valid_hostname(&host) {
+            rejected_lines += 1;
+        } else if exception {
+            allowed.push(host);
+        } else {
+            hosts.push(host);
+        }
+    }
+    ParsedHosts {
+        hosts,
+        allowed,
+        lines,
+        rejected_lines,
+        ignored_rules,
+    }
+}
+
+/// Guesses the format of a list from its first lines.
+///
+/// Lists with more Adblock-style lines than hosts entries are Adblock lists, the rest are
+/// hosts files. Never returns [`SourceFormat::Auto`].
+pub fn detect_format(content: &str) -> SourceFormat {
+    let mut adblock_lines = 0;
+    let mut hosts_lines = 0;
+    for line in content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .take(DETECT_LINES)
+    {
+        if line.starts_with("||")
+            || line.starts_with("@@")
+            || line.starts_with('!')
+            || line.starts_with("[Adblock")
+            || COSMETIC_MARKERS.iter().any(|marker| line.contains(marker))
+        {
+            adblock_lines += 1;
+        } else if line
+            .split_whitespace()
+            .next()
+            .is_some_and(|first| first.parse::<IpAddr>().is_ok() || first == "0")
+        {
+            hosts_lines += 1;
+        }
+    }
+    if adblock_lines > hosts_lines {
+        SourceFormat::Adblock
+    } else {
+        SourceFormat::Hosts
+    }
+}
+
+/// Parses the content of a source in the given format, detecting it first if needed.
+pub fn parse_source(content: &str, format: SourceFormat) -> ParsedHosts {
+    let format = match format {
+        SourceFormat::Auto => detect_format(content),
+        format => format,
+    };
+    match format {
+        SourceFormat::Adblock => parse_adblock(content),
+        _ => parse_hosts(content),
+    }
+}
+
+// Returns the URLs of the enabled sources whose parsed cached copy matches
+fn sources_matching(
+    sources: &[HostsSource],
+    matches: impl Fn(&ParsedHosts) -> bool,
+) -> Vec<String> {
+    sources
+        .iter()
+        .filter(|s| s.enabled)
+        .filter(|s| load_cached(s).is_some_and(|parsed| matches(&parsed)))
+        .map(|s| s.url.clone())
+        .collect()
+}
+
+/// Returns the URLs of the enabled sources whose cached copy blocks `host`.
+pub fn sources_blocking(sources: &[HostsSource], host: &str) -> Vec<String> {
+    sources_matching(sources, |parsed| parsed.hosts.iter().any(|h| h == host))
+}
+
+/// Returns the URLs of the enabled sources with an exception rule for `host`.
+pub fn sources_excepting(sources: &[HostsSource], host: &str) -> Vec<String> {
+    sources_matching(sources, |parsed| parsed.allowed.iter().any(|h| h == host))
+}
+
+// Parses the cached copy of a source, None if it has never been fetched or can't be read
+fn load_cached(source: &HostsSource) -> Option<ParsedHosts> {
+    let path = cache_path(&source.url);
+    if !path.exists() {
+        return None;
+    }
+    match fs::read(&path) {
+        Ok(content) => Some(parse_source(
+            &String::from_utf8_lossy(&content),
+            source.format,
+        )),
+        Err(e) => {
+            eprintln!("Failed to read cached hosts source {}: {}", source.url, e);
+            None
+        }
+    }
+}
+
+/// Returns every host the cached sources know of, whether the source is enabled or not
+/// and whether the host is blocked or excepted. Block patterns are matched against these.
+pub fn load_known_hosts(sources: &[HostsSource]) -> BTreeSet<String> {
+    let mut known_hosts = BTreeSet::new();
+    for parsed in sources.iter().filter_map(load_cached) {
+        known_hosts.extend(parsed.hosts);
+        known_hosts.extend(parsed.allowed);
+    }
+    known_hosts
+}
+
+/// Builds the deduplicated blocked hosts set from the cached copies of every enabled source.
+///
+/// The exception rules of every source are taken out of the set, whichever source blocks
+/// the host. Then the enabled block patterns add every known host they match, see
+/// [`load_known_hosts`], and the enabled custom blocked hosts are added whatever the sources
+/// say. Sources that have never been fetched are skipped.
+pub fn load_blocked_hosts(
+    sources: &[HostsSource],
+    custom_hosts: &[BlockedHost],
+    blocked_patterns: &[BlockedPattern],
+) -> (BTreeSet<String>, HashMap<String, SourceStats>) {
+    let mut blocked_hosts = BTreeSet::new();
+    let mut exceptions = BTreeSet::new();
+    // Hosts that aren't in the set yet, but that a block pattern may add
+    let mut candidates = BTreeSet::new();
+    let mut stats = HashMap::new();
+    let index = load_cache_index();
+    let patterns = rules::compile_rules(
+        blocked_patterns
+            .iter()
+            .filter(|p| p.enabled)
+            .map(|p| (p.pattern.as_str(), MatchMode::Regex)),
+    );
+    for source in sources.iter() {
+        // Disabled sources only matter as candidates for the block patterns
+        if !source.enabled && patterns.is_empty() {
+            continue;
+        }
+        let parsed = match load_cached(source) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        if !source.enabled {
+            candidates.extend(parsed.hosts);
+            candidates.extend(parsed.allowed);
+            continue;
+        }
+        stats.insert(
+            source.url.clone(),
+            SourceStats {
+                hosts: parsed.hosts.len(),
+                rejected_lines: parsed.rejected_lines,
+                exceptions: parsed.allowed.len(),
+                ignored_rules: parsed.ignored_rules,
+                rejected_update: index.get(&source.url).and_then(|m| m.rejected.clone()),
+                verified: index.get(&source.url).is_some_and(|m| m.verified),
+            },
+        );
+        blocked_hosts.extend(parsed.hosts);
+        exceptions.extend(parsed.allowed);
+    }
+    blocked_hosts.retain(|host| !exceptions.contains(host));
+    if !patterns.is_empty() {
+        candidates.extend(exceptions);
+        blocked_hosts.extend(
+            candidates
+                .into_iter()
+                .filter(|host| patterns.iter().any(|rule| rule.matches(host))),
+        );
+    }
+    blocked_hosts.extend(
+        custom_hosts
+            .iter()
+            .filter(|h| h.enabled)
+            .map(|h| h.host.clone()),
+    );
+    (blocked_hosts, stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hosts_accepts_every_sink() {
+        let parsed = parse_hosts(
+            "0.0.0.0 a.com\n127.0.0.1 b.com\n:: c.com\n::1 d.com\n0 e.com\n::0 f.com\ng.com\n",
+        );
+        assert_eq!(
+            parsed.hosts,
+            ["a.com", "b.com", "c.com", "d.com", "e.com", "f.com", "g.com"]
+        );
+        assert_eq!(parsed.lines, 7);
+        assert_eq!(parsed.rejected_lines, 0);
+    }
+
+    #[test]
+    fn parse_hosts_reads_several_hosts_per_line() {
+        let parsed = parse_hosts("0.0.0.0 a.com b.com\tc.com\n");
+        assert_eq!(parsed.hosts, ["a.com", "b.com", "c.com"]);
+        assert_eq!(parsed.lines, 1);
+    }
+
+    #[test]
+    fn parse_hosts_strips_comments() {
+        let parsed = parse_hosts("# a comment\n0.0.0.0 a.com # ads.com\n  # indented\n");
+        assert_eq!(parsed.hosts, ["a.com"]);
+        assert_eq!(parsed.lines, 1);
+        assert_eq!(parsed.rejected_lines, 0);
+    }
+
+    #[test]
+    fn parse_hosts_handles_crlf() {
+        let parsed = parse_hosts("0.0.0.0 a.com\r\n0.0.0.0 B.com.\r\n");
+        assert_eq!(parsed.hosts, ["a.com", "b.com"]);
+        assert_eq!(parsed.rejected_lines, 0);
+    }
+
+    #[test]
+    fn parse_hosts_counts_rejected_lines() {
+        let parsed = parse_hosts(
+            "0.0.0.0 a.com\n192.168.1.1 router.lan\n<html>\n0.0.0.0 -bad-.com\n0.0.0.0\n",
+        );
+        assert_eq!(parsed.hosts, ["a.com"]);
+        assert_eq!(parsed.lines, 5);
+        assert_eq!(parsed.rejected_lines, 4);
+    }
+
+    #[test]
+    fn parse_hosts_rejects_plain_text() {
+        let parsed = parse_hosts("Page not found\nexample.com\nlocalhost\nads.example.com oops\n");
+        assert_eq!(parsed.hosts, ["example.com"]);
+        assert_eq!(parsed.lines, 4);
+        assert_eq!(parsed.rejected_lines, 3);
+    }
+
+    #[test]
+    fn parse_hosts_skips_local_hostnames() {
+        let parsed = parse_hosts("127.0.0.1 localhost\n::1 ip6-localhost ip6-loopback\n");
+        assert!(parsed.hosts.is_empty());
+        assert_eq!(parsed.lines, 2);
+        assert_eq!(parsed.rejected_lines, 0);
+    }
+
+    #[test]
+    fn parse_adblock_reads_blocks_and_exceptions() {
+        let parsed = parse_adblock(
+            "[Adblock Plus 2.0]\n! comment\n||a.com^\n||B.com^$important\n@@||c.com^\n",
+        );
+        assert_eq!(parsed.hosts, ["a.com", "b.com"]);
+        assert_eq!(parsed.allowed, ["c.com"]);
+        assert_eq!(parsed.lines, 3);
+        assert_eq!(parsed.rejected_lines, 0);
+        assert_eq!(parsed.ignored_rules, 0);
+    }
+
+    #[test]
+    fn parse_adblock_ignores_rules_it_cant_use() {
+        let parsed = parse_adblock(
+            "example.com##.ad\n||a.com/ads^\n||b.com^$third-party\n/banner/\n||*.c.com^\n",
+        );
+        assert!(parsed.hosts.is_empty());
+        assert_eq!(parsed.lines, 5);
+        assert_eq!(parsed.ignored_rules, 5);
+        assert_eq!(parsed.rejected_lines, 0);
+    }
+
+    #[test]
+    fn parse_adblock_counts_rejected_lines() {
+        let parsed = parse_adblock("||a.com^\n||-bad-.com^\n");
+        assert_eq!(parsed.hosts, ["a.com"]);
+        assert_eq!(parsed.rejected_lines, 1);
+    }
+
+    #[test]
+    fn detect_format_tells_lists_apart() {
+        assert!(detect_format("! Title\n||a.com^\n||b.com^\n") == SourceFormat::Adblock);
+        assert!(detect_format("# Title\n0.0.0.0 a.com\n0.0.0.0 b.com\n") == SourceFormat::Hosts);
+        assert!(detect_format("0 a.com\n") == SourceFormat::Hosts);
+        assert!(detect_format("") == SourceFormat::Hosts);
+    }
+}
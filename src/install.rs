@@ -0,0 +1,426 @@
+use crate::rules::{self, MatchMode};
+use crate::settings::Settings;
+use crate::{atomic, AllowedHost, RedirectedHost, ARGS};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+
+// Everything between these two lines belongs to yawada
+pub const BEGIN_MARKER: &str = "# BEGIN yawada managed section";
+pub const END_MARKER: &str = "# END yawada managed section";
+// Start marker of a section appended to a file that didn't end with a line break.
+// The line break added before it belongs to the section and goes away with it.
+const BEGIN_MARKER_AFTER_BREAK: &str = "# BEGIN yawada managed section, line break added above";
+
+const HEADER_LINE: &str = "# This section is generated by yawada, changes will be overwritten";
+// Prefix of the line holding the digest of the section content
+const DIGEST_PREFIX: &str = "# Digest: ";
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstallState {
+    NotInstalled,
+    Current,
+    // Installed, but the sources or rules changed since then
+    Stale,
+    // The managed section was edited by hand or is malformed
+    Tampered,
+}
+
+impl fmt::Display for InstallState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            InstallState::NotInstalled => "Not installed",
+            InstallState::Current => "Installed",
+            InstallState::Stale => "Installed (outdated, press Install/Update)",
+            InstallState::Tampered => "Installed (modified outside of yawada)",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Returns the path of the system hosts file.
+pub fn system_hosts_path() -> PathBuf {
+    if cfg!(windows) {
+        let system_root =
+            std::env::var("SystemRoot").unwrap_or_else(|_| String::from("C:\\Windows"));
+        PathBuf::from(system_root)
+            .join("System32")
+            .join("drivers")
+            .join("etc")
+            .join("hosts")
+    } else {
+        PathBuf::from("/etc/hosts")
+    }
+}
+
+/// Returns the path of the system hosts file under an alternate root directory.
+pub fn hosts_path_in_root(root: &Path) -> PathBuf {
+    let relative: PathBuf = system_hosts_path()
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect();
+    root.join(relative)
+}
+
+/// Returns the absolute path of the hosts file yawada manages.
+///
+/// An explicit file wins over an alternate root, and for each of them the `--hosts-file`
+/// and `--root` flags win over the `YAWADA_HOSTS_FILE` and `YAWADA_ROOT` environment
+/// variables, which win over the settings. Relative paths are relative to the working directory.
+pub fn hosts_path(settings: &Settings) -> PathBuf {
+    let path = configured_hosts_path(settings);
+    std::path::absolute(&path).unwrap_or(path)
+}
+
+fn configured_hosts_path(settings: &Settings) -> PathBuf {
+    let non_empty = |s: &str| (!s.is_empty()).then(|| PathBuf::from(s));
+    let hosts_file = ARGS
+        .hosts_file
+        .clone()
+        .or_else(|| {
+            std::env::var_os("YAWADA_HOSTS_FILE")
+                .filter(|p| !p.is_empty())
+                .map(PathBuf::from)
+        })
+        .or_else(|| non_empty(&settings.hosts_file));
+    if let Some(hosts_file) = hosts_file {
+        return hosts_file;
+    }
+    let root = ARGS
+        .root
+        .clone()
+        .or_else(|| {
+            std::env::var_os("YAWADA_ROOT")
+                .filter(|p| !p.is_empty())
+                .map(PathBuf::from)
+        })
+        .or_else(|| non_empty(&settings.hosts_root));
+    match root {
+        Some(root) => hosts_path_in_root(&root),
+        None => system_hosts_path(),
+    }
+}
+
+/// Compiles the blocked, allowed and redirected hosts into the lines of the managed section.
+///
+/// Blocked hosts are pointed to the sink address of the settings, and also to the
+/// IPv6 one if IPv6 entries are on. The returned string doesn't include the markers.
+pub fn compile_hosts(
+    blocked_hosts: &BTreeSet<String>,
+    allowed_hosts: &[AllowedHost],
+    redirected_hosts: &[RedirectedHost],
+    settings: &Settings,
+) -> String {
+    let redirected_hosts: Vec<&RedirectedHost> =
+        redirected_hosts.iter().filter(|h| h.enabled).collect();
+    let allowed_hosts: Vec<&AllowedHost> = allowed_hosts.iter().filter(|h| h.enabled).collect();
+    let mut excluded: BTreeSet<&str> = allowed_hosts
+        .iter()
+        .filter(|h| h.mode == MatchMode::Exact)
+        .map(|h| h.host.as_str())
+        .collect();
+    // A redirected host shouldn't be blocked at the same time
+    excluded.extend(redirected_hosts.iter().map(|h| h.host.as_str()));
+    // Rules that can't be looked up in a set are checked against every blocked host
+    let patterns = rules::compile_rules(
+        allowed_hosts
+            .iter()
+            .filter(|h| h.mode != MatchMode::Exact)
+            .map(|h| (h.host.as_str(), h.mode)),
+    );
+    let mut content = String::new();
+    for host in blocked_hosts {
+        if excluded.contains(host.as_str()) || patterns.iter().any(|rule| rule.matches(host)) {
+            continue;
+        }
+        content.push_str(&format!("{} {}\n", settings.sink_address, host));
+        if settings.ipv6 {
+            content.push_str(&format!("{} {}\n", settings.sink_address_v6, host));
+        }
+    }
+    for host in redirected_hosts {
+        content.push_str(&format!("{} {}\n", host.ip, host.host));
+    }
+    content
+}
+
+/// Finds the byte range of the managed section (markers included) in a hosts file.
+///
+/// The range includes the line break yawada added before the section, if there is one.
+///
+/// Returns an error if the markers are unbalanced or there is more than one section.
+pub fn find_managed_section(content: &str) -> Result<Option<Range<usize>>, String> {
+    let mut section = None;
+    let mut start = None;
+    let mut offset = 0;
+    for line in content.split_inclusive('\n') {
+        let end = offset + line.len();
+        match line.trim_end() {
+            marker @ (BEGIN_MARKER | BEGIN_MARKER_AFTER_BREAK) => {
+                if start.is_some() {
+                    return Err("Found a yawada section start marker inside another section".into());
+                }
+                if section.is_some() {
+                    return Err("Found more than one yawada section".into());
+                }
+                let before = &content[..offset];
+                let added_break = if marker != BEGIN_MARKER_AFTER_BREAK {
+                    0
+                } else if before.ends_with("\r\n") {
+                    2
+                } else if before.ends_with('\n') {
+                    1
+                } else {
+                    0
+                };
+                start = Some(offset - added_break);
+            }
+            END_MARKER => match start.take() {
+                Some(start) => section = Some(start..end),
+                None => {
+                    return Err("Found a yawada section end marker without a start marker".into())
+                }
+            },
+            _ => {}
+        }
+        offset = end;
+    }
+    if start.is_some() {
+        return Err("Found a yawada section start marker without an end marker".into());
+    }
+    Ok(section)
+}
+
+// Hashes the lines of the section content, so line endings don't matter
+fn digest<'a>(lines: impl Iterator<Item = &'a str>) -> String {
+    let mut hasher = blake3::Hasher::new();
+    for line in lines {
+        hasher.update(line.as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher.finalize().to_hex().to_string()
+}
+
+/// Replaces the managed section of a hosts file with the compiled hosts,
+/// or appends one if there isn't any.
+///
+/// Lines outside of the section are kept as they are.
+pub fn replace_managed_section(content: &str, compiled: &str) -> Result<String, String> {
+    // Follow the line endings of the existing file
+    let newline = if content.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let range = find_managed_section(content)?;
+    let before = match &range {
+        Some(range) => &content[..range.start],
+        None => content,
+    };
+    let mut section = String::new();
+    // The section has to start on a line of its own
+    if !before.is_empty() && !before.ends_with('\n') {
+        section.push_str(newline);
+        section.push_str(BEGIN_MARKER_AFTER_BREAK);
+    } else {
+        section.push_str(BEGIN_MARKER);
+    }
+    section.push_str(newline);
+    section.push_str(HEADER_LINE);
+    section.push_str(newline);
+    section.push_str(DIGEST_PREFIX);
+    section.push_str(&digest(compiled.lines()));
+    section.push_str(newline);
+    for line in compiled.lines() {
+        section.push_str(line);
+        section.push_str(newline);
+    }
+    section.push_str(END_MARKER);
+    section.push_str(newline);
+    let after = match &range {
+        Some(range) => &content[range.end..],
+        None => "",
+    };
+    let mut new_content = String::with_capacity(content.len() + section.len());
+    new_content.push_str(before);
+    new_content.push_str(&section);
+    new_content.push_str(after);
+    Ok(new_content)
+}
+
+/// Writes the compiled hosts into the managed section of the hosts file at `path`.
+pub fn install(path: &Path, compiled: &str) -> Result<(), String> {
+    let content = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+    let new_content = replace_managed_section(&content, compiled)?;
+    atomic::rewrite_with_backup(path, new_content)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Removes the managed section from a hosts file, keeping everything else as it is.
+pub fn remove_managed_section(content: &str) -> Result<String, String> {
+    match find_managed_section(content)? {
+        Some(range) => {
+            let mut new_content = String::with_capacity(content.len() - range.len());
+            new_content.push_str(&content[..range.start]);
+            new_content.push_str(&content[range.end..]);
+            Ok(new_content)
+        }
+        None => Err("The hosts file doesn't contain a yawada section".into()),
+    }
+}
+
+/// Removes the managed section from the hosts file at `path`.
+pub fn uninstall(path: &Path) -> Result<(), String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let new_content = remove_managed_section(&content)?;
+    atomic::rewrite_with_backup(path, new_content)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Inspects the hosts file content and compares its managed section with the compiled hosts.
+pub fn install_state(content: &str, compiled: &str) -> InstallState {
+    let range = match find_managed_section(content) {
+        Ok(Some(range)) => range,
+        Ok(None) => return InstallState::NotInstalled,
+        Err(_) => return InstallState::Tampered,
+    };
+    // Drop the line break added before the section
+    let section = &content[range];
+    let section = section
+        .strip_prefix("\r\n")
+        .or_else(|| section.strip_prefix('\n'))
+        .unwrap_or(section);
+    let mut lines = section.lines();
+    // Skip the begin marker, the end marker is dropped below
+    lines.next();
+    if lines.next() != Some(HEADER_LINE) {
+        return InstallState::Tampered;
+    }
+    let stored_digest = match lines.next().and_then(|l| l.strip_prefix(DIGEST_PREFIX)) {
+        Some(d) => d.trim().to_string(),
+        None => return InstallState::Tampered,
+    };
+    let mut body: Vec<&str> = lines.collect();
+    body.pop();
+    if digest(body.into_iter()) != stored_digest {
+        return InstallState::Tampered;
+    }
+    if digest(compiled.lines()) != stored_digest {
+        return InstallState::Stale;
+    }
+    InstallState::Current
+}
+
+/// Reads the hosts file at `path` and returns its install state.
+pub fn read_install_state(path: &Path, compiled: &str) -> InstallState {
+    match fs::read_to_string(path) {
+        Ok(content) => install_state(&content, compiled),
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path.display(), e);
+            InstallState::NotInstalled
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPILED: &str = "0.0.0.0 a.com\n0.0.0.0 b.com\n";
+
+    // Installs and uninstalls, checking that the original bytes come back
+    fn round_trip(original: &str) -> String {
+        let installed = replace_managed_section(original, COMPILED).unwrap();
+        assert_eq!(remove_managed_section(&installed).unwrap(), original);
+        installed
+    }
+
+    #[test]
+    fn round_trip_keeps_user_lines() {
+        let installed = round_trip("127.0.0.1 localhost\n");
+        assert!(installed.starts_with("127.0.0.1 localhost\n# BEGIN yawada managed section\n"));
+        round_trip("");
+    }
+
+    #[test]
+    fn round_trip_without_trailing_newline() {
+        let installed = round_trip("127.0.0.1 localhost");
+        assert!(installed.starts_with("127.0.0.1 localhost\n"));
+        round_trip("127.0.0.1 localhost\r\n::1 localhost");
+    }
+
+    #[test]
+    fn round_trip_with_crlf() {
+        let installed = round_trip("127.0.0.1 localhost\r\n::1 localhost\r\n");
+        assert!(!installed.replace("\r\n", "").contains('\n'));
+        assert!(install_state(&installed, COMPILED) == InstallState::Current);
+    }
+
+    #[test]
+    fn reinstall_replaces_the_section() {
+        let compiled = "0.0.0.0 c.com\n";
+        for original in ["127.0.0.1 localhost\n", "127.0.0.1 localhost", ""] {
+            let installed = replace_managed_section(original, COMPILED).unwrap();
+            let reinstalled = replace_managed_section(&installed, compiled).unwrap();
+            assert_eq!(
+                reinstalled,
+                replace_managed_section(original, compiled).unwrap()
+            );
+            assert_eq!(remove_managed_section(&reinstalled).unwrap(), original);
+        }
+    }
+
+    #[test]
+    fn reinstall_keeps_lines_after_the_section() {
+        let installed = replace_managed_section("a\n", COMPILED).unwrap() + "b\n";
+        let reinstalled = replace_managed_section(&installed, "0.0.0.0 c.com\n").unwrap();
+        assert!(reinstalled.starts_with("a\n# BEGIN"));
+        assert!(reinstalled.ends_with(&format!("{}\nb\n", END_MARKER)));
+        assert_eq!(remove_managed_section(&reinstalled).unwrap(), "a\nb\n");
+    }
+
+    #[test]
+    fn unbalanced_markers_are_refused() {
+        let section = replace_managed_section("", COMPILED).unwrap();
+        let begin = format!("{}\n", BEGIN_MARKER);
+        let end = format!("{}\n", END_MARKER);
+        let invalid = [
+            begin.clone(),
+            end.clone(),
+            format!("{}{}", end, begin),
+            format!("{}{}", begin, section),
+            format!("{}{}", section, section),
+        ];
+        for content in invalid.iter() {
+            assert!(find_managed_section(content).is_err());
+            assert!(replace_managed_section(content, COMPILED).is_err());
+            assert!(remove_managed_section(content).is_err());
+            assert!(install_state(content, COMPILED) == InstallState::Tampered);
+        }
+        assert!(remove_managed_section("127.0.0.1 localhost\n").is_err());
+    }
+
+    #[test]
+    fn install_state_detects_changes() {
+        let original = "127.0.0.1 localhost\n";
+        assert!(install_state(original, COMPILED) == InstallState::NotInstalled);
+        let installed = replace_managed_section(original, COMPILED).unwrap();
+        assert!(install_state(&installed, COMPILED) == InstallState::Current);
+        assert!(install_state(&installed, "0.0.0.0 c.com\n") == InstallState::Stale);
+        let edited = installed.replace("0.0.0.0 b.com", "0.0.0.0 c.com");
+        assert!(install_state(&edited, COMPILED) == InstallState::Tampered);
+        // User lines outside of the section don't matter
+        let appended = installed + "10.0.0.1 nas\n";
+        assert!(install_state(&appended, COMPILED) == InstallState::Current);
+    }
+}
@@ -1,29 +1,63 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
-use blake3;
 use chrono::prelude::*;
-use directories::{self, ProjectDirs};
 use eframe::egui;
 use egui_modal::Modal;
-use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
 use std::fs;
+use std::net::IpAddr;
 use std::path::PathBuf;
-use std::sync::LazyLock;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, LazyLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
-static PROJECT_DIRS: LazyLock<ProjectDirs> = LazyLock::new(|| {
-    // Hardcode the name for now
-    let proj_dirs = directories::ProjectDirs::from("me", "tretrauit", "yawada")
-        .expect("Failed to get config directory");
-    proj_dirs
-});
+mod args;
+mod atomic;
+mod backup;
+mod cli;
+mod config;
+mod daemon;
+mod fetch;
+mod helper;
+mod hosts;
+mod install;
+mod network;
+mod paths;
+mod rules;
+mod schedule;
+mod settings;
+mod validate;
+mod verify;
 
-static CLIENT: LazyLock<Client> = LazyLock::new(|| {
-    let client = Client::new();
-    client
-});
+static ARGS: LazyLock<args::Args> =
+    LazyLock::new(|| match args::Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, args::USAGE);
+            std::process::exit(2);
+        }
+    });
+
+static PROJECT_DIRS: LazyLock<paths::AppDirs> = LazyLock::new(paths::AppDirs::locate);
+
+// How often the window asks the daemon for its status
+const DAEMON_POLL_INTERVAL: Duration = Duration::from_secs(2);
 
 fn main() -> eframe::Result {
+    if ARGS.help {
+        println!("{}", args::USAGE);
+        return Ok(());
+    }
+    if ARGS.helper {
+        std::process::exit(helper::run_helper());
+    }
+    if ARGS.headless || ARGS.command == ["daemon"] {
+        std::process::exit(daemon::run());
+    }
+    if !ARGS.command.is_empty() {
+        std::process::exit(cli::run(&ARGS.command));
+    }
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default().with_inner_size([720.0, 560.0]),
         ..Default::default()
@@ -35,37 +69,276 @@ fn main() -> eframe::Result {
     )
 }
 
+// New fields must have a default, so configs written before them still load
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 struct AllowedHost {
     host: String,
     enabled: bool,
+    // How the host is matched against the blocked hosts
+    mode: rules::MatchMode,
+}
+
+impl Default for AllowedHost {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            enabled: true,
+            mode: rules::MatchMode::Exact,
+        }
+    }
+}
+
+// Host blocked by the user, whatever the sources say
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct BlockedHost {
+    host: String,
+    enabled: bool,
+    // What the entry is for, only shown in the editor
+    note: String,
+}
+
+impl Default for BlockedHost {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            enabled: true,
+            note: String::new(),
+        }
+    }
 }
 
+// Regex that blocks every known host it matches, see hosts::load_known_hosts
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct BlockedPattern {
+    pattern: String,
+    enabled: bool,
+}
+
+impl Default for BlockedPattern {
+    fn default() -> Self {
+        Self {
+            pattern: String::new(),
+            enabled: true,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 struct HostsSource {
     url: String,
     last_updated: u64,
+    // When the source was last checked for changes, even if it had none
+    last_checked: u64,
     enabled: bool,
+    // Pinned digest of the content, as blake3:<hex> or sha256:<hex>
+    checksum: String,
+    // Minisign public key the content must be signed with
+    public_key: String,
+    // URL of the detached signature, defaults to the source URL with .minisig appended
+    signature_url: String,
+    // Content of inline sources
+    content: String,
+    // Hours between automatic updates of this source, 0 uses the global interval
+    update_interval_hours: u64,
+    // Syntax of the list, detected from the content by default
+    format: hosts::SourceFormat,
+}
+
+impl Default for HostsSource {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            last_updated: 0,
+            last_checked: 0,
+            enabled: true,
+            checksum: String::new(),
+            public_key: String::new(),
+            signature_url: String::new(),
+            content: String::new(),
+            update_interval_hours: 0,
+            format: hosts::SourceFormat::Auto,
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 struct RedirectedHost {
     host: String,
     ip: String,
     enabled: bool,
 }
 
+impl Default for RedirectedHost {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            ip: String::new(),
+            enabled: true,
+        }
+    }
+}
+
+// Work that may wait on the daemon or an authorization prompt, so it runs on another thread
+enum Task {
+    Install,
+    // Installing again after a scheduled update, only if the installed section is outdated
+    Reinstall,
+    Uninstall,
+    UpdateNow,
+    SetSources,
+    ReloadConfig,
+    // Restoring the snapshot at this path
+    Restore(PathBuf),
+}
+
+// A task's answer, with the new status of the daemon if it went through the daemon
+type TaskResult = Result<Option<daemon::Status>, String>;
+
+// Runs a task on another thread, its result is picked up by the next frame
+fn start_task(
+    ctx: &egui::Context,
+    tasks: &mut Vec<(Task, Receiver<TaskResult>)>,
+    task: Task,
+    run: impl FnOnce() -> TaskResult + Send + 'static,
+) {
+    let (sender, receiver) = mpsc::channel();
+    let ctx = ctx.clone();
+    thread::spawn(move || {
+        let _ = sender.send(run());
+        ctx.request_repaint();
+    });
+    tasks.push((task, receiver));
+}
+
+// Sends a request to the running daemon without waiting for its answer
+fn start_daemon_request(
+    ctx: &egui::Context,
+    tasks: &mut Vec<(Task, Receiver<TaskResult>)>,
+    task: Task,
+    request: daemon::Request,
+) {
+    start_task(ctx, tasks, task, move || {
+        daemon::request(&request).map(Some)
+    });
+}
+
+// Blocked hosts and managed section, parsed and compiled on another thread
+struct Compiled {
+    // Only set if the cached sources were parsed again
+    blocked: Option<(
+        Arc<BTreeSet<String>>,
+        HashMap<String, hosts::SourceStats>,
+        hosts::CacheIndex,
+    )>,
+    compiled: String,
+    state: install::InstallState,
+    // Whether the hosts file should be installed again if it's outdated
+    auto_install: bool,
+}
+
+// Whether one of the tasks writes the hosts file, only one may run at a time
+fn changes_hosts_file(tasks: &[(Task, Receiver<TaskResult>)]) -> bool {
+    tasks.iter().any(|(task, _)| {
+        matches!(
+            task,
+            Task::Install | Task::Reinstall | Task::Uninstall | Task::Restore(_)
+        )
+    })
+}
+
+// Tells the daemon to read the config again, if it's running
+fn reload_daemon_config(
+    ctx: &egui::Context,
+    daemon_status: &Option<daemon::Status>,
+    tasks: &mut Vec<(Task, Receiver<TaskResult>)>,
+) {
+    if daemon_status.is_some() {
+        start_daemon_request(
+            ctx,
+            tasks,
+            Task::ReloadConfig,
+            daemon::Request::ReloadConfig,
+        );
+    }
+}
+
 struct MyApp {
-    blocked_hosts: Vec<String>,
+    // Shared with the thread compiling the managed section
+    blocked_hosts: Arc<BTreeSet<String>>,
+    // Parse statistics for each source, keyed by URL
+    hosts_sources_stats: HashMap<String, hosts::SourceStats>,
+    // Metadata of the cached sources, loaded with the blocked hosts
+    cache_index: hosts::CacheIndex,
+    // Number of blocked hosts each allow rule matches, keyed by pattern and mode
+    allow_preview: HashMap<(String, rules::MatchMode), Result<usize, String>>,
+    // The user's own block list, merged into blocked_hosts
+    custom_blocked_hosts: Vec<BlockedHost>,
+    // Hosts pasted into the block list editor, one per line or in hosts format
+    pasted_blocked_hosts: String,
+    // What adding the pasted hosts did
+    pasted_blocked_summary: String,
+    blocked_patterns: Vec<BlockedPattern>,
+    // Hosts the block patterns are matched against, loaded when their editor is open
+    known_hosts: Option<BTreeSet<String>>,
+    // Number of known hosts each block pattern matches
+    block_preview: HashMap<String, Result<usize, String>>,
     allowed_hosts: Vec<AllowedHost>,
     redirected_hosts: Vec<RedirectedHost>,
-    is_hosts_file_installed: bool,
+    hosts_file_state: install::InstallState,
+    // Managed section compiled from the current hosts and rules
+    compiled: String,
+    // Running parse and compile, and what was asked for since it started
+    compile_job: Option<Receiver<Compiled>>,
+    reload_pending: bool,
+    refresh_pending: bool,
+    auto_install_pending: bool,
+    settings: settings::Settings,
     hosts_sources: Vec<HostsSource>,
     hosts_sources_last_updated: u64,
+    // Running or last finished source update
+    fetch_job: Option<fetch::FetchJob>,
+    // Whether the fetch job was started by the scheduler
+    scheduled_fetch: bool,
+    scheduler: schedule::Scheduler,
+    // Status of the running daemon, which takes over updates and installs
+    daemon_status: Option<daemon::Status>,
+    daemon_polled_at: Option<Instant>,
+    // Answer to the running status request, if there is one
+    daemon_poll: Option<Receiver<Option<daemon::Status>>>,
+    // Whether the daemon answered at least once, until then we don't know if it runs
+    daemon_known: bool,
+    // Whether we asked the daemon for an update and wait for its results
+    daemon_update_requested: bool,
+    // Running tasks, each with the receiver of its result
+    tasks: Vec<(Task, Receiver<TaskResult>)>,
+    // Dark mode and UI scale the window currently uses
+    applied_appearance: Option<(bool, f32)>,
+    backup_settings: backup::BackupSettings,
+    backup_snapshots: Vec<backup::Snapshot>,
+    // The hosts file the listed snapshots were taken of
+    backup_target: PathBuf,
+    selected_snapshot: Option<usize>,
+    // Diff between the selected snapshot and the current hosts file
+    snapshot_diff: Vec<String>,
     // UI parts
     show_edit_sources: bool,
     show_edit_allowed_hosts: bool,
+    show_edit_blocked_hosts: bool,
+    show_edit_blocked_patterns: bool,
     show_edit_redirect_hosts: bool,
+    show_backups: bool,
+    show_edit_install_target: bool,
+    show_settings: bool,
+    show_schedule_settings: bool,
+    // Settings being edited in the settings windows, applied when they are saved
+    install_target_draft: settings::Settings,
+    settings_draft: settings::Settings,
+    schedule_draft: settings::Settings,
     show_confirmation_dialog: bool,
     allowed_to_close: bool,
     // HACK
@@ -77,16 +350,55 @@ struct MyApp {
 impl Default for MyApp {
     fn default() -> Self {
         Self {
-            blocked_hosts: vec![],
+            blocked_hosts: Arc::new(BTreeSet::new()),
+            hosts_sources_stats: HashMap::new(),
+            cache_index: HashMap::new(),
+            allow_preview: HashMap::new(),
+            custom_blocked_hosts: vec![],
+            pasted_blocked_hosts: String::new(),
+            pasted_blocked_summary: String::new(),
+            blocked_patterns: vec![],
+            known_hosts: None,
+            block_preview: HashMap::new(),
             allowed_hosts: vec![],
             redirected_hosts: vec![],
-            is_hosts_file_installed: false,
+            hosts_file_state: install::InstallState::NotInstalled,
+            compiled: String::new(),
+            compile_job: None,
+            reload_pending: false,
+            refresh_pending: false,
+            auto_install_pending: false,
+            settings: settings::Settings::default(),
             hosts_sources: vec![],
             hosts_sources_last_updated: 0,
+            fetch_job: None,
+            scheduled_fetch: false,
+            scheduler: schedule::Scheduler::default(),
+            daemon_status: None,
+            daemon_polled_at: None,
+            daemon_poll: None,
+            daemon_known: false,
+            daemon_update_requested: false,
+            tasks: vec![],
+            applied_appearance: None,
+            backup_settings: backup::BackupSettings::default(),
+            backup_snapshots: vec![],
+            backup_target: PathBuf::new(),
+            selected_snapshot: None,
+            snapshot_diff: vec![],
             show_confirmation_dialog: false,
             show_edit_sources: false,
             show_edit_allowed_hosts: false,
+            show_edit_blocked_hosts: false,
+            show_edit_blocked_patterns: false,
             show_edit_redirect_hosts: false,
+            show_backups: false,
+            show_edit_install_target: false,
+            show_settings: false,
+            show_schedule_settings: false,
+            install_target_draft: settings::Settings::default(),
+            settings_draft: settings::Settings::default(),
+            schedule_draft: settings::Settings::default(),
             allowed_to_close: false,
             first_run: true,
             dialog_error_body: String::new(),
@@ -95,8 +407,72 @@ impl Default for MyApp {
     }
 }
 
+impl MyApp {
+    // Parses the cached sources if asked to, then compiles the managed section and reads
+    // the hosts file on another thread, the source lists may be megabytes long
+    fn start_compile(&mut self, ctx: &egui::Context) {
+        let reload = std::mem::take(&mut self.reload_pending);
+        self.refresh_pending = false;
+        let auto_install = std::mem::take(&mut self.auto_install_pending);
+        let sources = self.hosts_sources.clone();
+        let custom_blocked_hosts = self.custom_blocked_hosts.clone();
+        let blocked_patterns = self.blocked_patterns.clone();
+        let blocked_hosts = self.blocked_hosts.clone();
+        let allowed_hosts = self.allowed_hosts.clone();
+        let redirected_hosts = self.redirected_hosts.clone();
+        let settings = self.settings.clone();
+        let (sender, receiver) = mpsc::channel();
+        let ctx = ctx.clone();
+        thread::spawn(move || {
+            let blocked = reload.then(|| {
+                let (blocked_hosts, stats) =
+                    hosts::load_blocked_hosts(&sources, &custom_blocked_hosts, &blocked_patterns);
+                (Arc::new(blocked_hosts), stats, hosts::load_cache_index())
+            });
+            let blocked_hosts = match &blocked {
+                Some((blocked_hosts, _, _)) => blocked_hosts.clone(),
+                None => blocked_hosts,
+            };
+            let compiled = install::compile_hosts(
+                &blocked_hosts,
+                &allowed_hosts,
+                &redirected_hosts,
+                &settings,
+            );
+            let state = install::read_install_state(&install::hosts_path(&settings), &compiled);
+            let _ = sender.send(Compiled {
+                blocked,
+                compiled,
+                state,
+                auto_install,
+            });
+            ctx.request_repaint();
+        });
+        self.compile_job = Some(receiver);
+    }
+}
+
 impl eframe::App for MyApp {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        // Follow the appearance settings, they are applied as soon as they change
+        let dark_mode = match self.settings.theme {
+            settings::Theme::System => frame.info().system_theme != Some(eframe::Theme::Light),
+            settings::Theme::Light => false,
+            settings::Theme::Dark => true,
+        };
+        let ui_scale = self
+            .settings
+            .ui_scale
+            .clamp(settings::MIN_UI_SCALE, settings::MAX_UI_SCALE);
+        if self.applied_appearance != Some((dark_mode, ui_scale)) {
+            ctx.set_visuals(if dark_mode {
+                egui::Visuals::dark()
+            } else {
+                egui::Visuals::light()
+            });
+            ctx.set_zoom_factor(ui_scale);
+            self.applied_appearance = Some((dark_mode, ui_scale));
+        }
         // Generic modal
         let modal = Modal::new(ctx, "generic_modal");
         modal.show(|ui| {
@@ -112,9 +488,196 @@ impl eframe::App for MyApp {
             self.dialog_error_body = body;
             modal.open();
         };
+        // Set when the cached sources need to be parsed again
+        let mut reload_blocked_hosts = false;
+        // Set when the hosts file or the rules compiled into it may have changed
+        let mut refresh_hosts_file_state = false;
+        // Set when a scheduled update changed the sources and the hosts file should follow
+        let mut auto_install = false;
+        if let Some(results) = self.fetch_job.as_mut().and_then(|job| job.poll_finished()) {
+            schedule::apply_results(&mut self.hosts_sources, &results);
+            for (url, status) in results.iter() {
+                match status {
+                    fetch::FetchStatus::Done { fetched_at, .. } => {
+                        println!("Fetched hosts source {}", url);
+                        self.hosts_sources_last_updated = *fetched_at;
+                        reload_blocked_hosts = true;
+                        auto_install = self.scheduled_fetch && self.settings.auto_install;
+                    }
+                    fetch::FetchStatus::Unchanged { .. } => {
+                        println!("Hosts source {} is unchanged", url);
+                    }
+                    // Shows the reason next to the source
+                    fetch::FetchStatus::Rejected(_) => reload_blocked_hosts = true,
+                    _ => {}
+                }
+            }
+            // Keep the update times, so the scheduler knows about them after a restart
+            if let Err(e) = schedule::record_results(&results) {
+                println!("Failed to save hosts sources: {}", e);
+            }
+            if self.scheduled_fetch {
+                // Don't interrupt the user for background updates
+                print!("{}", fetch::summary(&results));
+            } else {
+                show_modal("Update finished".to_string(), fetch::summary(&results));
+            }
+        }
+        // The daemon may take a while to answer, e.g. while it waits for an authorization
+        // prompt, so it's asked on another thread to keep the window responsive
+        let answer = match self.daemon_poll.as_ref().map(Receiver::try_recv) {
+            Some(Ok(status)) => Some(status),
+            Some(Err(TryRecvError::Disconnected)) => Some(None),
+            Some(Err(TryRecvError::Empty)) | None => None,
+        };
+        if let Some(new_status) = answer {
+            self.daemon_poll = None;
+            self.daemon_known = true;
+            let previous = std::mem::replace(&mut self.daemon_status, new_status);
+            if let (Some(previous), Some(status)) = (previous, &self.daemon_status) {
+                if status.last_finished != previous.last_finished {
+                    // The daemon saved the update times and the cached sources
+                    schedule::apply_results(&mut self.hosts_sources, &status.progress);
+                    for (_, status) in status.progress.iter() {
+                        if let fetch::FetchStatus::Done { fetched_at, .. } = status {
+                            self.hosts_sources_last_updated = *fetched_at;
+                        }
+                    }
+                    reload_blocked_hosts = true;
+                    if self.daemon_update_requested {
+                        self.daemon_update_requested = false;
+                        show_modal(
+                            "Update finished".to_string(),
+                            fetch::summary(&status.progress),
+                        );
+                    }
+                }
+            }
+        }
+        let mut finished = vec![];
+        let mut i = 0;
+        while i < self.tasks.len() {
+            let result = match self.tasks[i].1.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => {
+                    i += 1;
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => Err("The task stopped without an answer".into()),
+            };
+            let (task, _) = self.tasks.remove(i);
+            finished.push((task, result));
+        }
+        for (task, result) in finished {
+            if matches!(
+                task,
+                Task::Install | Task::Reinstall | Task::Uninstall | Task::Restore(_)
+            ) {
+                refresh_hosts_file_state = true;
+            }
+            if let Task::Restore(_) = task {
+                // Restoring took a snapshot of the current file first
+                self.backup_snapshots = backup::list_snapshots(&self.backup_target);
+                self.selected_snapshot = None;
+                self.snapshot_diff.clear();
+            }
+            match result {
+                Ok(status) => {
+                    if status.is_some() {
+                        self.daemon_status = status;
+                    }
+                    match task {
+                        Task::Install => println!("Installed hosts file"),
+                        // Logged by the task, it may have had nothing to do
+                        Task::Reinstall => {}
+                        Task::Uninstall => println!("Uninstalled hosts file"),
+                        Task::UpdateNow => self.daemon_update_requested = true,
+                        Task::SetSources => {
+                            println!("Saved hosts sources");
+                            // Sources may have been enabled, disabled or removed
+                            reload_blocked_hosts = true;
+                            self.show_edit_sources = false;
+                        }
+                        Task::Restore(path) => println!("Restored {}", path.display()),
+                        Task::ReloadConfig => {}
+                    }
+                }
+                Err(e) => {
+                    let message = match task {
+                        Task::Install | Task::Reinstall => {
+                            format!("Failed to install hosts file: {}", e)
+                        }
+                        Task::Uninstall => format!("Failed to uninstall hosts file: {}", e),
+                        Task::UpdateNow => format!("Failed to start the update: {}", e),
+                        Task::SetSources => format!("Failed to save hosts sources: {}", e),
+                        Task::Restore(_) => format!("Failed to restore hosts file: {}", e),
+                        Task::ReloadConfig => {
+                            eprintln!("Failed to reload the config of the daemon: {}", e);
+                            continue;
+                        }
+                    };
+                    println!("{}", message);
+                    show_modal("Error".to_string(), message);
+                }
+            }
+        }
+        let poll_daemon = match self.daemon_polled_at {
+            Some(polled_at) => polled_at.elapsed() >= DAEMON_POLL_INTERVAL,
+            None => true,
+        };
+        if poll_daemon && self.daemon_poll.is_none() && !self.first_run {
+            self.daemon_polled_at = Some(Instant::now());
+            let (sender, receiver) = mpsc::channel();
+            let ctx = ctx.clone();
+            thread::spawn(move || {
+                let _ = sender.send(daemon::status());
+                ctx.request_repaint();
+            });
+            self.daemon_poll = Some(receiver);
+        }
+        if self.daemon_status.is_some() {
+            ctx.request_repaint_after(DAEMON_POLL_INTERVAL);
+        }
+        let mut fetching = self
+            .fetch_job
+            .as_ref()
+            .is_some_and(|job| !job.is_finished())
+            || self.daemon_status.as_ref().is_some_and(|s| s.updating);
+        if self.settings.auto_update && self.daemon_known && self.daemon_status.is_none() {
+            if !fetching {
+                let due = self.scheduler.poll(&self.hosts_sources, &self.settings);
+                if !due.is_empty() {
+                    println!("Starting scheduled update of {} hosts sources", due.len());
+                    self.fetch_job = Some(fetch::FetchJob::start(
+                        ctx,
+                        due,
+                        network::RetryPolicy::from_settings(&self.settings),
+                    ));
+                    self.scheduled_fetch = true;
+                    fetching = true;
+                }
+            }
+            // Keep the scheduler running while the window is idle
+            ctx.request_repaint_after(Duration::from_secs(schedule::TICK_SECS));
+        }
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.menu_button("Menu", |ui| {
                 if ui.button("Settings").clicked() {
+                    self.settings_draft = self.settings.clone();
+                    self.show_settings = true;
+                    ui.close_menu();
+                }
+                if ui.button("Automatic updates").clicked() {
+                    self.schedule_draft = self.settings.clone();
+                    self.show_schedule_settings = true;
+                    ui.close_menu();
+                }
+                if ui.button("Backups").clicked() {
+                    self.backup_target = install::hosts_path(&self.settings);
+                    self.backup_snapshots = backup::list_snapshots(&self.backup_target);
+                    self.selected_snapshot = None;
+                    self.snapshot_diff.clear();
+                    self.show_backups = true;
                     ui.close_menu();
                 }
             });
@@ -124,10 +687,18 @@ impl eframe::App for MyApp {
             ui.heading("Statistics");
             ui.horizontal(|ui| {
                 ui.label(format!("Blocked hosts: {}", self.blocked_hosts.len()));
-                // I don't want to implement this so okay :)
-                // if ui.button("Edit").clicked() {
-                //     println!("TODO");
-                // }
+                if self.compile_job.is_some() {
+                    ui.spinner();
+                }
+                if ui.button("Edit").clicked() {
+                    self.show_edit_blocked_hosts = true;
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label(format!("Block patterns: {}", self.blocked_patterns.len()));
+                if ui.button("Edit").clicked() {
+                    self.show_edit_blocked_patterns = true;
+                }
             });
             ui.horizontal(|ui| {
                 ui.label(format!("Allowed hosts: {}", self.allowed_hosts.len()));
@@ -141,16 +712,62 @@ impl eframe::App for MyApp {
                     self.show_edit_redirect_hosts = true;
                 }
             });
-            ui.label(format!(
-                "Is hosts file installed?: {}",
-                self.is_hosts_file_installed
-            ));
+            ui.label(format!("Hosts file: {}", self.hosts_file_state));
             ui.horizontal(|ui| {
-                if ui.button("Install/Update").clicked() {
-                    println!("TODO");
+                ui.label(format!(
+                    "Target: {}",
+                    install::hosts_path(&self.settings).display()
+                ));
+                if ui.button("Edit").clicked() {
+                    self.install_target_draft = self.settings.clone();
+                    self.show_edit_install_target = true;
+                }
+            });
+            ui.horizontal(|ui| {
+                // The compiled section is outdated until the running compile is done
+                let installing = changes_hosts_file(&self.tasks) || self.compile_job.is_some();
+                if ui
+                    .add_enabled(!installing, egui::Button::new("Install/Update"))
+                    .clicked()
+                {
+                    if self.daemon_status.is_some() {
+                        start_daemon_request(
+                            ctx,
+                            &mut self.tasks,
+                            Task::Install,
+                            daemon::Request::Install,
+                        );
+                    } else {
+                        let hosts_path = install::hosts_path(&self.settings);
+                        let compiled = self.compiled.clone();
+                        let retention = self.backup_settings.retention;
+                        start_task(ctx, &mut self.tasks, Task::Install, move || {
+                            backup::snapshot_and_prune(&hosts_path, retention)?;
+                            helper::perform(&helper::Request::install(hosts_path, compiled))?;
+                            Ok(None)
+                        });
+                    }
                 }
-                if ui.button("Uninstall").clicked() {
-                    println!("TODO");
+                if ui
+                    .add_enabled(!installing, egui::Button::new("Uninstall"))
+                    .clicked()
+                {
+                    if self.daemon_status.is_some() {
+                        start_daemon_request(
+                            ctx,
+                            &mut self.tasks,
+                            Task::Uninstall,
+                            daemon::Request::Uninstall,
+                        );
+                    } else {
+                        let hosts_path = install::hosts_path(&self.settings);
+                        let retention = self.backup_settings.retention;
+                        start_task(ctx, &mut self.tasks, Task::Uninstall, move || {
+                            backup::snapshot_and_prune(&hosts_path, retention)?;
+                            helper::perform(&helper::Request::uninstall(hosts_path))?;
+                            Ok(None)
+                        });
+                    }
                 }
             });
             ui.add_space(10.0);
@@ -171,52 +788,60 @@ impl eframe::App for MyApp {
                 }
             ));
             ui.horizontal(|ui| {
-                if ui.button("Update").clicked() {
-                    for (i, source) in self.hosts_sources.clone().into_iter().enumerate() {
-                        // Actually update the source
-                        match CLIENT.get(source.url.clone()).send() {
-                            Ok(response) => {
-                                let body = response.text().unwrap();
-                                let file_name =
-                                    blake3::hash(source.url.as_bytes()).to_hex().to_string();
-                                let config_dir = PROJECT_DIRS.config_dir();
-                                let hosts_sources_path =
-                                    config_dir.join("hosts_sources").join(file_name);
-                                match fs::write(&hosts_sources_path, body) {
-                                    Ok(_) => {
-                                        println!("Fetched hosts source for index: {}", i);
-                                        let current_time = SystemTime::now()
-                                            .duration_since(UNIX_EPOCH)
-                                            .unwrap()
-                                            .as_secs();
-                                        self.hosts_sources[i].last_updated = current_time;
-                                        self.hosts_sources_last_updated = current_time;
-                                    }
-                                    Err(e) => {
-                                        println!("Failed to fetch hosts source: {}", e);
-                                        show_modal(
-                                            "Error".to_string(),
-                                            format!("Failed to fetch hosts source: {}", e),
-                                        );
-                                        return;
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                println!("Failed to fetch hosts source: {}", e);
-                                show_modal(
-                                    "Error".to_string(),
-                                    format!("Failed to fetch hosts source: {}", e),
-                                );
-                                return;
-                            }
-                        }
+                if ui
+                    .add_enabled(!fetching, egui::Button::new("Update"))
+                    .clicked()
+                {
+                    if self.daemon_status.is_some() {
+                        start_daemon_request(
+                            ctx,
+                            &mut self.tasks,
+                            Task::UpdateNow,
+                            daemon::Request::UpdateNow { urls: vec![] },
+                        );
+                        return;
                     }
+                    let sources = self
+                        .hosts_sources
+                        .iter()
+                        .filter(|s| s.enabled)
+                        .cloned()
+                        .collect();
+                    self.fetch_job = Some(fetch::FetchJob::start(
+                        ctx,
+                        sources,
+                        network::RetryPolicy::from_settings(&self.settings),
+                    ));
+                    self.scheduled_fetch = false;
                 }
                 if ui.button("Edit sources").clicked() {
                     self.show_edit_sources = true;
                 }
             });
+            if let Some(status) = &self.daemon_status {
+                ui.label("Updates and installs are handled by the running daemon.");
+                if status.updating {
+                    for (url, status) in status.progress.iter() {
+                        ui.label(format!("{}: {}", url, status));
+                    }
+                }
+            }
+            let mut dismiss_fetch_job = false;
+            if let Some(job) = &self.fetch_job {
+                for (url, status) in job.progress() {
+                    ui.label(format!("{}: {}", url, status));
+                }
+                if !job.is_finished() {
+                    if ui.button("Cancel").clicked() {
+                        job.cancel();
+                    }
+                } else if ui.button("Dismiss").clicked() {
+                    dismiss_fetch_job = true;
+                }
+            }
+            if dismiss_fetch_job {
+                self.fetch_job = None;
+            }
         });
         // Modals
         // Conflict modal
@@ -254,11 +879,12 @@ impl eframe::App for MyApp {
             });
         });
         // End of modals
-        if ctx.input(|i| i.viewport().close_requested()) {
-            if !self.allowed_to_close {
-                ctx.send_viewport_cmd(egui::ViewportCommand::CancelClose);
-                close_confirmation_modal.open();
-            }
+        if ctx.input(|i| i.viewport().close_requested())
+            && !self.allowed_to_close
+            && self.settings.confirm_on_close
+        {
+            ctx.send_viewport_cmd(egui::ViewportCommand::CancelClose);
+            close_confirmation_modal.open();
         }
         // First run of the loop
         if self.first_run {
@@ -266,65 +892,50 @@ impl eframe::App for MyApp {
             let config_dir = PROJECT_DIRS.config_dir();
             println!("Config dir: {:?}", config_dir);
             // Creates yawada/config and yawada/config/host_sources/ directories
-            fs::create_dir_all(&config_dir.join("hosts_sources")).unwrap();
+            fs::create_dir_all(config_dir.join("hosts_sources")).unwrap();
             println!("Created config directory");
-            let hosts_sources_path = config_dir.join("hosts_sources.json");
-            if hosts_sources_path.exists() {
-                println!("Hosts sources file exists, loading...");
-                let hosts_sources_file = fs::read_to_string(hosts_sources_path).unwrap();
-                let hosts_sources: Vec<HostsSource> =
-                    match serde_json::from_str(&hosts_sources_file) {
-                        Ok(s) => s,
-                        Err(e) => {
-                            println!("Failed to load hosts sources file: {}", e);
-                            show_modal(
-                                "Error".to_string(),
-                                format!("Failed to load hosts sources file: {}", e),
-                            );
-                            vec![]
+            match config::load_with_warnings() {
+                Ok((config, warnings)) => {
+                    println!("Loaded config file");
+                    self.hosts_sources = config.hosts_sources;
+                    if !self.hosts_sources.is_empty() {
+                        self.hosts_sources_last_updated = self.hosts_sources[0].last_updated;
+                    }
+                    self.allowed_hosts = config.allowed_hosts;
+                    self.custom_blocked_hosts = config.blocked_hosts;
+                    self.blocked_patterns = config.blocked_patterns;
+                    self.redirected_hosts = config.redirected_hosts;
+                    self.settings = config.settings;
+                    self.backup_settings = config.backup;
+                    if let Err(e) = network::configure(&self.settings) {
+                        println!("Failed to apply network settings: {}", e);
+                        show_modal(
+                            "Error".to_string(),
+                            format!("Failed to apply network settings: {}", e),
+                        );
+                    }
+                    if !warnings.is_empty() {
+                        for warning in warnings.iter() {
+                            println!("{}", warning);
                         }
-                    };
-                self.hosts_sources = hosts_sources;
-                if self.hosts_sources.len() > 0 {
-                    self.hosts_sources_last_updated = self.hosts_sources[0].last_updated;
+                        show_modal(
+                            "Warning".to_string(),
+                            format!(
+                                "Parts of the config couldn't be read and were skipped:\n{}",
+                                warnings.join("\n")
+                            ),
+                        );
+                    }
+                }
+                Err(e) => {
+                    println!("Failed to load config file: {}", e);
+                    show_modal(
+                        "Error".to_string(),
+                        format!("Failed to load config file: {}", e),
+                    );
                 }
             }
-            let allowed_hosts_path = config_dir.join("allowed_hosts.json");
-            if allowed_hosts_path.exists() {
-                println!("Allowed hosts file exists, loading...");
-                let allowed_hosts_file = fs::read_to_string(allowed_hosts_path).unwrap();
-                let allowed_hosts: Vec<AllowedHost> =
-                    match serde_json::from_str(&allowed_hosts_file) {
-                        Ok(s) => s,
-                        Err(e) => {
-                            println!("Failed to load alllowed hosts file: {}", e);
-                            show_modal(
-                                "Error".to_string(),
-                                format!("Failed to load allowed hosts file: {}", e),
-                            );
-                            vec![]
-                        }
-                    };
-                self.allowed_hosts = allowed_hosts;
-            }
-            let redirected_hosts_path = config_dir.join("redirected_hosts.json");
-            if redirected_hosts_path.exists() {
-                println!("Redirected hosts file exists, loading...");
-                let redirected_hosts_file = fs::read_to_string(redirected_hosts_path).unwrap();
-                let redirected_hosts: Vec<RedirectedHost> =
-                    match serde_json::from_str(&redirected_hosts_file) {
-                        Ok(s) => s,
-                        Err(e) => {
-                            println!("Failed to load redirected hosts file: {}", e);
-                            show_modal(
-                                "Error".to_string(),
-                                format!("Failed to load redirected hosts file: {}", e),
-                            );
-                            vec![]
-                        }
-                    };
-                self.redirected_hosts = redirected_hosts;
-            }
+            reload_blocked_hosts = true;
             self.first_run = false;
         }
         if self.show_edit_allowed_hosts {
@@ -333,18 +944,18 @@ impl eframe::App for MyApp {
                 .resizable(true)
                 .show(ctx, |ui| {
                     ui.label("Allowed hosts are used to allow a host to be accessed.");
+                    ui.label("With subdomains also allows every host under it, a glob matches * to any run of characters and ? to a single one.");
+                    ui.label("A regex has to match the whole host.");
                     ui.horizontal(|ui| {
                         if ui.button("Add").clicked() {
-                            self.allowed_hosts.push(AllowedHost {
-                                host: String::new(),
-                                enabled: true,
-                            });
+                            self.allowed_hosts.push(AllowedHost::default());
                         }
                         if ui.button("Save & Close").clicked() {
                             // Check if there is conflicting sources
                             // If there is, show a dialog
                             let mut urls: Vec<String> = vec![];
-                            for sources in self.allowed_hosts.iter() {
+                            for sources in self.allowed_hosts.iter_mut() {
+                                sources.host = rules::normalize_pattern(&sources.host, sources.mode);
                                 if sources.host.is_empty() {
                                     host_url_empty_modal.open();
                                     return;
@@ -354,17 +965,21 @@ impl eframe::App for MyApp {
                                     conflict_hosts_modal.open();
                                     return;
                                 }
+                                if let Err(e) = rules::validate_pattern(&sources.host, sources.mode)
+                                {
+                                    show_modal("Error".to_string(), e);
+                                    return;
+                                }
                                 urls.push(sources.host.clone());
                             }
                             // Actually save the sources
-                            let config_dir = PROJECT_DIRS.config_dir();
-                            let hosts_sources_path = config_dir.join("allowed_hosts.json");
-                            match fs::write(
-                                hosts_sources_path,
-                                serde_json::to_string(&self.allowed_hosts).unwrap(),
-                            ) {
+                            match config::update(|config| {
+                                config.allowed_hosts = self.allowed_hosts.clone()
+                            }) {
                                 Ok(_) => {
                                     println!("Saved allowed hosts");
+                                    reload_daemon_config(ctx, &self.daemon_status, &mut self.tasks);
+                                    refresh_hosts_file_state = true;
                                 }
                                 Err(e) => {
                                     println!("Failed to save allowed hosts: {}", e);
@@ -389,6 +1004,33 @@ impl eframe::App for MyApp {
                             }
                             ui.checkbox(&mut self.allowed_hosts[i].enabled, "");
                             ui.text_edit_singleline(&mut self.allowed_hosts[i].host);
+                            let allowed_host = &mut self.allowed_hosts[i];
+                            egui::ComboBox::from_id_source(("allowed_mode", i))
+                                .selected_text(allowed_host.mode.label())
+                                .show_ui(ui, |ui| {
+                                    for mode in rules::MatchMode::ALL {
+                                        ui.selectable_value(
+                                            &mut allowed_host.mode,
+                                            mode,
+                                            mode.label(),
+                                        );
+                                    }
+                                });
+                            // Cached, counting may have to go through every blocked host
+                            let pattern = rules::normalize_pattern(&allowed_host.host, allowed_host.mode);
+                            let key = (pattern, allowed_host.mode);
+                            let removed = self.allow_preview.entry(key).or_insert_with_key(|key| {
+                                rules::Rule::new(&key.0, key.1)
+                                    .map(|rule| rule.count_matches(&self.blocked_hosts))
+                            });
+                            match removed {
+                                Ok(removed) => {
+                                    ui.weak(format!("Removes {} blocked entries", removed));
+                                }
+                                Err(e) => {
+                                    ui.colored_label(ui.visuals().error_fg_color, e.as_str());
+                                }
+                            }
                             if ui.button("X").clicked() {
                                 println!("Removing index: {}", i);
                                 self.allowed_hosts.remove(i);
@@ -397,13 +1039,213 @@ impl eframe::App for MyApp {
                     }
                 });
         }
+        if self.show_edit_blocked_hosts {
+            egui::Window::new("Blocked hosts")
+                .collapsible(false)
+                .resizable(true)
+                .show(ctx, |ui| {
+                    ui.label("Blocked hosts are your own block list, they are blocked even if a source has an exception for them.");
+                    ui.horizontal(|ui| {
+                        if ui.button("Add").clicked() {
+                            self.custom_blocked_hosts.push(BlockedHost::default());
+                        }
+                        if ui.button("Save & Close").clicked() {
+                            // Check if there is conflicting hosts
+                            // If there is, show a dialog
+                            let mut urls: Vec<String> = vec![];
+                            for blocked_host in self.custom_blocked_hosts.iter_mut() {
+                                blocked_host.host = blocked_host
+                                    .host
+                                    .trim()
+                                    .trim_end_matches('.')
+                                    .to_ascii_lowercase();
+                                if blocked_host.host.is_empty() {
+                                    host_url_empty_modal.open();
+                                    return;
+                                }
+                                if urls.contains(&blocked_host.host) {
+                                    conflict_hosts_modal.open();
+                                    return;
+                                }
+                                if !hosts::is_valid_hostname(&blocked_host.host) {
+                                    show_modal(
+                                        "Error".to_string(),
+                                        format!("Invalid hostname: {}", blocked_host.host),
+                                    );
+                                    return;
+                                }
+                                urls.push(blocked_host.host.clone());
+                            }
+                            match config::update(|config| {
+                                config.blocked_hosts = self.custom_blocked_hosts.clone()
+                            }) {
+                                Ok(_) => {
+                                    println!("Saved blocked hosts");
+                                    reload_daemon_config(ctx, &self.daemon_status, &mut self.tasks);
+                                    reload_blocked_hosts = true;
+                                }
+                                Err(e) => {
+                                    println!("Failed to save blocked hosts: {}", e);
+                                    show_modal(
+                                        "Error".to_string(),
+                                        format!("Failed to save blocked hosts: {}", e),
+                                    );
+                                    return;
+                                }
+                            }
+                            self.pasted_blocked_summary.clear();
+                            self.show_edit_blocked_hosts = false;
+                        }
+                    });
+                    egui::CollapsingHeader::new("Paste hosts").show(ui, |ui| {
+                        ui.label("One host per line, hosts file lines work too.");
+                        ui.add(
+                            egui::TextEdit::multiline(&mut self.pasted_blocked_hosts)
+                                .code_editor()
+                                .desired_rows(6),
+                        );
+                        ui.horizontal(|ui| {
+                            if ui.button("Add pasted hosts").clicked() {
+                                let parsed = hosts::parse_hosts(&self.pasted_blocked_hosts);
+                                let mut added = 0;
+                                for host in parsed.hosts {
+                                    if self.custom_blocked_hosts.iter().any(|h| h.host == host) {
+                                        continue;
+                                    }
+                                    self.custom_blocked_hosts.push(BlockedHost {
+                                        host,
+                                        ..Default::default()
+                                    });
+                                    added += 1;
+                                }
+                                self.pasted_blocked_summary = format!(
+                                    "Added {} hosts, skipped {} invalid lines",
+                                    added, parsed.rejected_lines
+                                );
+                                self.pasted_blocked_hosts.clear();
+                            }
+                            ui.label(&self.pasted_blocked_summary);
+                        });
+                    });
+                    egui::ScrollArea::vertical().show(ui, |ui| {
+                        // Create a list of hosts so we can modify them ourselves
+                        let custom_blocked_hosts = self.custom_blocked_hosts.clone();
+                        for (i, _) in custom_blocked_hosts.iter().enumerate() {
+                            ui.horizontal(|ui| {
+                                // Stop if we reach the end of the list
+                                if i == self.custom_blocked_hosts.len() {
+                                    return;
+                                }
+                                let blocked_host = &mut self.custom_blocked_hosts[i];
+                                ui.checkbox(&mut blocked_host.enabled, "");
+                                ui.text_edit_singleline(&mut blocked_host.host);
+                                ui.add(
+                                    egui::TextEdit::singleline(&mut blocked_host.note)
+                                        .hint_text("Note"),
+                                );
+                                if ui.button("X").clicked() {
+                                    println!("Removing index: {}", i);
+                                    self.custom_blocked_hosts.remove(i);
+                                }
+                            });
+                        }
+                    });
+                });
+        }
+        if self.show_edit_blocked_patterns {
+            egui::Window::new("Block patterns")
+                .collapsible(false)
+                .resizable(true)
+                .show(ctx, |ui| {
+                    ui.label("Block patterns are regexes that block every host the sources know of they match, even in disabled sources.");
+                    ui.label("A pattern has to match the whole host, allowed hosts still win over it.");
+                    ui.horizontal(|ui| {
+                        if ui.button("Add").clicked() {
+                            self.blocked_patterns.push(BlockedPattern::default());
+                        }
+                        if ui.button("Save & Close").clicked() {
+                            let mut patterns: Vec<String> = vec![];
+                            for blocked_pattern in self.blocked_patterns.iter_mut() {
+                                blocked_pattern.pattern = blocked_pattern.pattern.trim().to_string();
+                                if blocked_pattern.pattern.is_empty() {
+                                    host_url_empty_modal.open();
+                                    return;
+                                }
+                                if patterns.contains(&blocked_pattern.pattern) {
+                                    conflict_hosts_modal.open();
+                                    return;
+                                }
+                                if let Err(e) = rules::compile_regex(&blocked_pattern.pattern) {
+                                    show_modal("Error".to_string(), e);
+                                    return;
+                                }
+                                patterns.push(blocked_pattern.pattern.clone());
+                            }
+                            match config::update(|config| {
+                                config.blocked_patterns = self.blocked_patterns.clone()
+                            }) {
+                                Ok(_) => {
+                                    println!("Saved block patterns");
+                                    reload_daemon_config(ctx, &self.daemon_status, &mut self.tasks);
+                                    reload_blocked_hosts = true;
+                                }
+                                Err(e) => {
+                                    println!("Failed to save block patterns: {}", e);
+                                    show_modal(
+                                        "Error".to_string(),
+                                        format!("Failed to save block patterns: {}", e),
+                                    );
+                                    return;
+                                }
+                            }
+                            self.show_edit_blocked_patterns = false;
+                        }
+                    });
+                    let known_hosts = self
+                        .known_hosts
+                        .get_or_insert_with(|| hosts::load_known_hosts(&self.hosts_sources));
+                    ui.weak(format!("Known hosts: {}", known_hosts.len()));
+                    let blocked_patterns = self.blocked_patterns.clone();
+                    for (i, _) in blocked_patterns.iter().enumerate() {
+                        ui.horizontal(|ui| {
+                            if i == self.blocked_patterns.len() {
+                                return;
+                            }
+                            ui.checkbox(&mut self.blocked_patterns[i].enabled, "");
+                            ui.text_edit_singleline(&mut self.blocked_patterns[i].pattern);
+                            // Cached, the regex is compiled once and run against every known host
+                            let pattern = self.blocked_patterns[i].pattern.trim().to_string();
+                            let matched = self.block_preview.entry(pattern).or_insert_with_key(|pattern| {
+                                rules::Rule::new(pattern, rules::MatchMode::Regex)
+                                    .map(|rule| rule.count_matches(known_hosts))
+                            });
+                            match matched {
+                                Ok(matched) => {
+                                    ui.weak(format!("Matches {} known hosts", matched));
+                                }
+                                Err(e) => {
+                                    ui.colored_label(ui.visuals().error_fg_color, e.as_str());
+                                }
+                            }
+                            if ui.button("X").clicked() {
+                                println!("Removing index: {}", i);
+                                self.blocked_patterns.remove(i);
+                            }
+                        });
+                    }
+                });
+        }
         if self.show_edit_redirect_hosts {
             egui::Window::new("Redirected hosts")
                 .collapsible(false)
                 .resizable(true)
                 .show(ctx, |ui| {
-                    ui.label("Redirected hosts are used to redirect a host to a specific IP address.");
-                    ui.label("The left column is the host, and the right column is the IP address.");
+                    ui.label(
+                        "Redirected hosts are used to redirect a host to a specific IP address.",
+                    );
+                    ui.label(
+                        "The left column is the host, and the right column is the IP address.",
+                    );
                     ui.horizontal(|ui| {
                         if ui.button("Add").clicked() {
                             self.redirected_hosts.push(RedirectedHost {
@@ -416,7 +1258,12 @@ impl eframe::App for MyApp {
                             // Check if there is conflicting sources
                             // If there is, show a dialog
                             let mut urls: Vec<String> = vec![];
-                            for sources in self.redirected_hosts.iter() {
+                            for sources in self.redirected_hosts.iter_mut() {
+                                sources.host = sources
+                                    .host
+                                    .trim()
+                                    .trim_end_matches('.')
+                                    .to_ascii_lowercase();
                                 if sources.host.is_empty() {
                                     host_url_empty_modal.open();
                                     return;
@@ -426,17 +1273,34 @@ impl eframe::App for MyApp {
                                     conflict_hosts_modal.open();
                                     return;
                                 }
+                                if !hosts::is_valid_hostname(&sources.host) {
+                                    show_modal(
+                                        "Error".to_string(),
+                                        format!("Invalid hostname: {}", sources.host),
+                                    );
+                                    return;
+                                }
+                                // Stored the way the CLI stores it, so both compare equal
+                                match sources.ip.trim().parse::<IpAddr>() {
+                                    Ok(ip) => sources.ip = ip.to_string(),
+                                    Err(_) => {
+                                        show_modal(
+                                            "Error".to_string(),
+                                            format!("Invalid IP address: {}", sources.ip),
+                                        );
+                                        return;
+                                    }
+                                }
                                 urls.push(sources.host.clone());
                             }
                             // Actually save the sources
-                            let config_dir = PROJECT_DIRS.config_dir();
-                            let hosts_sources_path = config_dir.join("redirected_hosts.json");
-                            match fs::write(
-                                hosts_sources_path,
-                                serde_json::to_string(&self.redirected_hosts).unwrap(),
-                            ) {
+                            match config::update(|config| {
+                                config.redirected_hosts = self.redirected_hosts.clone()
+                            }) {
                                 Ok(_) => {
                                     println!("Saved redirected hosts");
+                                    reload_daemon_config(ctx, &self.daemon_status, &mut self.tasks);
+                                    refresh_hosts_file_state = true;
                                 }
                                 Err(e) => {
                                     println!("Failed to save redirected hosts: {}", e);
@@ -475,15 +1339,19 @@ impl eframe::App for MyApp {
                 .collapsible(false)
                 .resizable(true)
                 .show(ctx, |ui| {
+                    ui.label("Sources can be http(s) URLs, file:// URLs or local paths, or inline:<name> for a list edited here.");
                     ui.horizontal(|ui| {
                         if ui.button("Add").clicked() {
-                            self.hosts_sources.push(HostsSource {
-                                url: String::new(),
-                                last_updated: 0,
-                                enabled: true,
-                            });
+                            self.hosts_sources.push(HostsSource::default());
                         }
-                        if ui.button("Save & Close").clicked() {
+                        let saving = self
+                            .tasks
+                            .iter()
+                            .any(|(task, _)| matches!(task, Task::SetSources));
+                        if ui
+                            .add_enabled(!saving, egui::Button::new("Save & Close"))
+                            .clicked()
+                        {
                             // Check if there is conflicting sources
                             // If there is, show a dialog
                             let mut urls: Vec<String> = vec![];
@@ -499,15 +1367,27 @@ impl eframe::App for MyApp {
                                 }
                                 urls.push(sources.url.clone());
                             }
-                            // Actually save the sources
-                            let config_dir = PROJECT_DIRS.config_dir();
-                            let hosts_sources_path = config_dir.join("hosts_sources.json");
-                            match fs::write(
-                                hosts_sources_path,
-                                serde_json::to_string(&self.hosts_sources).unwrap(),
-                            ) {
+                            // Actually save the sources, through the daemon if it's running.
+                            // The window closes once the daemon has saved them.
+                            if self.daemon_status.is_some() {
+                                let request = daemon::Request::SetSources {
+                                    sources: self.hosts_sources.clone(),
+                                };
+                                start_daemon_request(
+                                    ctx,
+                                    &mut self.tasks,
+                                    Task::SetSources,
+                                    request,
+                                );
+                                return;
+                            }
+                            match config::update(|config| {
+                                config.hosts_sources = self.hosts_sources.clone()
+                            }) {
                                 Ok(_) => {
                                     println!("Saved hosts sources");
+                                    // Sources may have been enabled, disabled or removed
+                                    reload_blocked_hosts = true;
                                 }
                                 Err(e) => {
                                     println!("Failed to save hosts sources: {}", e);
@@ -545,7 +1425,53 @@ impl eframe::App for MyApp {
                                     datetime.format("%Y-%m-%d %H:%M:%S").to_string()
                                 }
                             ));
-                            let update_btn = ui.button("Update");
+                            ui.label(format!(
+                                "Last checked: {}",
+                                if self.hosts_sources[i].last_checked == 0 {
+                                    String::from("Never")
+                                } else {
+                                    let datetime = DateTime::from_timestamp(
+                                        self.hosts_sources[i].last_checked as i64,
+                                        0,
+                                    )
+                                    .unwrap();
+                                    datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+                                }
+                            ));
+                            if let Some(stats) =
+                                self.hosts_sources_stats.get(&self.hosts_sources[i].url)
+                            {
+                                ui.label(format!(
+                                    "Hosts: {}, rejected lines: {}",
+                                    stats.hosts, stats.rejected_lines
+                                ));
+                                if stats.exceptions > 0 || stats.ignored_rules > 0 {
+                                    ui.label(format!(
+                                        "Exceptions: {}, ignored rules: {}",
+                                        stats.exceptions, stats.ignored_rules
+                                    ));
+                                }
+                                if stats.verified {
+                                    ui.colored_label(egui::Color32::GREEN, "Verified");
+                                } else {
+                                    ui.weak("Unverified");
+                                }
+                                if let Some(reason) = &stats.rejected_update {
+                                    ui.colored_label(
+                                        ui.visuals().warn_fg_color,
+                                        format!("Last update rejected: {}", reason),
+                                    );
+                                }
+                            }
+                            if let Some(job) = &self.fetch_job {
+                                let url = &self.hosts_sources[i].url;
+                                if let Some((_, status)) =
+                                    job.progress().into_iter().find(|(u, _)| u == url)
+                                {
+                                    ui.label(status.to_string());
+                                }
+                            }
+                            let update_btn = ui.add_enabled(!fetching, egui::Button::new("Update"));
                             if update_btn.clicked() {
                                 // Check if there is conflicting sources
                                 // If there is, show a dialog
@@ -563,53 +1489,523 @@ impl eframe::App for MyApp {
                                     urls.push(sources.url.clone());
                                 }
                                 // Actually update the source
-                                match CLIENT.get(&self.hosts_sources[i].url).send() {
-                                    Ok(response) => {
-                                        let body = response.text().unwrap();
-                                        let file_name =
-                                            blake3::hash(&self.hosts_sources[i].url.as_bytes())
-                                                .to_hex()
-                                                .to_string();
-                                        let config_dir = PROJECT_DIRS.config_dir();
-                                        let hosts_sources_path =
-                                            config_dir.join("hosts_sources").join(file_name);
-                                        match fs::write(&hosts_sources_path, body) {
-                                            Ok(_) => {
-                                                println!("Fetched hosts source");
-                                            }
-                                            Err(e) => {
-                                                println!("Failed to fetch hosts source: {}", e);
-                                                show_modal(
-                                                    "Error".to_string(),
-                                                    format!("Failed to fetch hosts source: {}", e),
-                                                );
-                                                return;
-                                            }
-                                        }
-                                    }
-                                    Err(e) => {
-                                        println!("Failed to fetch hosts source: {}", e);
-                                        show_modal(
-                                            "Error".to_string(),
-                                            format!("Failed to fetch hosts source: {}", e),
-                                        );
-                                        return;
-                                    }
+                                if self.daemon_status.is_some() {
+                                    let request = daemon::Request::UpdateNow {
+                                        urls: vec![self.hosts_sources[i].url.clone()],
+                                    };
+                                    start_daemon_request(
+                                        ctx,
+                                        &mut self.tasks,
+                                        Task::UpdateNow,
+                                        request,
+                                    );
+                                    return;
                                 }
-                                let current_time = SystemTime::now()
-                                    .duration_since(UNIX_EPOCH)
-                                    .unwrap()
-                                    .as_secs();
-                                self.hosts_sources[i].last_updated = current_time;
-                                self.hosts_sources_last_updated = current_time;
+                                self.fetch_job = Some(fetch::FetchJob::start(
+                                    ui.ctx(),
+                                    vec![self.hosts_sources[i].clone()],
+                                    network::RetryPolicy::from_settings(&self.settings),
+                                ));
+                                self.scheduled_fetch = false;
                             }
                             if ui.button("X").clicked() {
                                 println!("Removing index: {}", i);
                                 self.hosts_sources.remove(i);
                             }
                         });
+                        if i >= self.hosts_sources.len() {
+                            continue;
+                        }
+                        if self.hosts_sources[i].url.starts_with(hosts::INLINE_PREFIX) {
+                            egui::CollapsingHeader::new("Content")
+                                .id_source(("content", i))
+                                .show(ui, |ui| {
+                                    ui.label("Hosts entries of this source, press Update to apply them.");
+                                    ui.add(
+                                        egui::TextEdit::multiline(
+                                            &mut self.hosts_sources[i].content,
+                                        )
+                                        .code_editor()
+                                        .desired_rows(8),
+                                    );
+                                });
+                        }
+                        ui.horizontal(|ui| {
+                            ui.label("Format:");
+                            let format = &mut self.hosts_sources[i].format;
+                            ui.radio_value(format, hosts::SourceFormat::Auto, "Detect");
+                            ui.radio_value(format, hosts::SourceFormat::Hosts, "Hosts file");
+                            ui.radio_value(format, hosts::SourceFormat::Adblock, "Adblock filters");
+                        });
+                        egui::CollapsingHeader::new("Schedule")
+                            .id_source(("schedule", i))
+                            .show(ui, |ui| {
+                                ui.horizontal(|ui| {
+                                    ui.label("Update every (hours, 0 uses the global interval):");
+                                    ui.add(
+                                        egui::DragValue::new(
+                                            &mut self.hosts_sources[i].update_interval_hours,
+                                        )
+                                        .range(0..=schedule::MAX_INTERVAL_HOURS),
+                                    );
+                                });
+                                let next_check = schedule::next_check(
+                                    &self.hosts_sources[i],
+                                    &self.settings,
+                                    &self.cache_index,
+                                );
+                                ui.label(format!(
+                                    "Next check: {}",
+                                    if !self.settings.auto_update {
+                                        String::from("Automatic updates are off")
+                                    } else if next_check == 0 {
+                                        String::from("Now")
+                                    } else {
+                                        let datetime =
+                                            DateTime::from_timestamp(next_check as i64, 0)
+                                                .unwrap();
+                                        datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+                                    }
+                                ));
+                            });
+                        egui::CollapsingHeader::new("Verification")
+                            .id_source(("verification", i))
+                            .show(ui, |ui| {
+                                let source = &mut self.hosts_sources[i];
+                                ui.horizontal(|ui| {
+                                    ui.label("Checksum (blake3:<hex> or sha256:<hex>):");
+                                    ui.text_edit_singleline(&mut source.checksum);
+                                });
+                                ui.horizontal(|ui| {
+                                    ui.label("Minisign public key:");
+                                    ui.text_edit_singleline(&mut source.public_key);
+                                });
+                                ui.horizontal(|ui| {
+                                    ui.label("Signature URL:");
+                                    let hint = verify::signature_url(source);
+                                    ui.add(
+                                        egui::TextEdit::singleline(&mut source.signature_url)
+                                            .hint_text(hint),
+                                    );
+                                });
+                            });
                     }
                 });
         }
+        if self.show_edit_install_target {
+            egui::Window::new("Install target")
+                .collapsible(false)
+                .resizable(true)
+                .show(ctx, |ui| {
+                    ui.label("The hosts file yawada installs into, leave empty to use the system one.");
+                    ui.label("The --hosts-file and --root flags and the YAWADA_HOSTS_FILE and YAWADA_ROOT environment variables take priority over these.");
+                    ui.horizontal(|ui| {
+                        ui.label("Hosts file:");
+                        ui.text_edit_singleline(&mut self.install_target_draft.hosts_file);
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Alternate root:");
+                        ui.text_edit_singleline(&mut self.install_target_draft.hosts_root);
+                    });
+                    ui.horizontal(|ui| {
+                    if ui.button("Save & Close").clicked() {
+                        let mut settings = self.settings.clone();
+                        settings.hosts_file = self.install_target_draft.hosts_file.clone();
+                        settings.hosts_root = self.install_target_draft.hosts_root.clone();
+                        match settings::save(&settings) {
+                            Ok(_) => {
+                                println!("Saved settings");
+                                self.settings = settings;
+                                reload_daemon_config(ctx, &self.daemon_status, &mut self.tasks);
+                                refresh_hosts_file_state = true;
+                            }
+                            Err(e) => {
+                                println!("Failed to save settings: {}", e);
+                                show_modal(
+                                    "Error".to_string(),
+                                    format!("Failed to save settings: {}", e),
+                                );
+                                return;
+                            }
+                        }
+                        self.show_edit_install_target = false;
+                    }
+                    if ui.button("Cancel").clicked() {
+                        self.show_edit_install_target = false;
+                    }
+                    });
+                });
+        }
+        if self.show_settings {
+            egui::Window::new("Settings")
+                .collapsible(false)
+                .resizable(true)
+                .show(ctx, |ui| {
+                    ui.heading("Hosts file");
+                    egui::Grid::new("hosts_file_settings_grid")
+                        .num_columns(2)
+                        .show(ui, |ui| {
+                            ui.label("Sink address:");
+                            ui.text_edit_singleline(&mut self.settings_draft.sink_address);
+                            ui.end_row();
+                            ui.label("IPv6 entries:");
+                            ui.checkbox(
+                                &mut self.settings_draft.ipv6,
+                                "Also block hosts over IPv6",
+                            );
+                            ui.end_row();
+                            ui.label("IPv6 sink address:");
+                            ui.add_enabled(
+                                self.settings_draft.ipv6,
+                                egui::TextEdit::singleline(
+                                    &mut self.settings_draft.sink_address_v6,
+                                ),
+                            );
+                            ui.end_row();
+                        });
+                    ui.heading("Network");
+                    egui::Grid::new("network_settings_grid")
+                        .num_columns(2)
+                        .show(ui, |ui| {
+                            ui.label("Proxy:");
+                            ui.add(
+                                egui::TextEdit::singleline(&mut self.settings_draft.proxy)
+                                    .hint_text("socks5://127.0.0.1:1080"),
+                            );
+                            ui.end_row();
+                            ui.label("Connect timeout (seconds):");
+                            ui.add(
+                                egui::DragValue::new(&mut self.settings_draft.connect_timeout_secs)
+                                    .range(1..=600),
+                            );
+                            ui.end_row();
+                            ui.label("Read timeout (seconds):");
+                            ui.add(
+                                egui::DragValue::new(&mut self.settings_draft.read_timeout_secs)
+                                    .range(1..=3600),
+                            );
+                            ui.end_row();
+                            ui.label("User-Agent:");
+                            ui.text_edit_singleline(&mut self.settings_draft.user_agent);
+                            ui.end_row();
+                            ui.label("Retry attempts:");
+                            ui.add(
+                                egui::DragValue::new(&mut self.settings_draft.retry_attempts)
+                                    .range(1..=10),
+                            );
+                            ui.end_row();
+                            ui.label("First retry delay (seconds):");
+                            ui.add(
+                                egui::DragValue::new(&mut self.settings_draft.retry_delay_secs)
+                                    .range(0..=300),
+                            );
+                            ui.end_row();
+                            ui.label("Extra root certificates:");
+                            ui.add(
+                                egui::TextEdit::multiline(&mut self.settings_draft.ca_certificates)
+                                    .hint_text("One PEM file path per line"),
+                            );
+                            ui.end_row();
+                        });
+                    ui.heading("Appearance");
+                    egui::Grid::new("appearance_settings_grid")
+                        .num_columns(2)
+                        .show(ui, |ui| {
+                            ui.label("Theme:");
+                            ui.horizontal(|ui| {
+                                ui.radio_value(
+                                    &mut self.settings_draft.theme,
+                                    settings::Theme::System,
+                                    "System",
+                                );
+                                ui.radio_value(
+                                    &mut self.settings_draft.theme,
+                                    settings::Theme::Light,
+                                    "Light",
+                                );
+                                ui.radio_value(
+                                    &mut self.settings_draft.theme,
+                                    settings::Theme::Dark,
+                                    "Dark",
+                                );
+                            });
+                            ui.end_row();
+                            ui.label("UI scale:");
+                            ui.add(
+                                egui::DragValue::new(&mut self.settings_draft.ui_scale)
+                                    .range(settings::MIN_UI_SCALE..=settings::MAX_UI_SCALE)
+                                    .speed(0.05),
+                            );
+                            ui.end_row();
+                        });
+                    ui.heading("Behaviour");
+                    ui.checkbox(
+                        &mut self.settings_draft.confirm_on_close,
+                        "Ask before closing the window",
+                    );
+                    let validation = settings::validate(&self.settings_draft);
+                    if let Err(e) = &validation {
+                        ui.colored_label(ui.visuals().warn_fg_color, e);
+                    }
+                    ui.horizontal(|ui| {
+                        if ui
+                            .add_enabled(validation.is_ok(), egui::Button::new("Save & Close"))
+                            .clicked()
+                        {
+                            // The install target and the schedule have their own windows
+                            let mut settings = self.settings_draft.clone();
+                            settings.hosts_file = self.settings.hosts_file.clone();
+                            settings.hosts_root = self.settings.hosts_root.clone();
+                            settings.auto_update = self.settings.auto_update;
+                            settings.auto_update_interval_hours =
+                                self.settings.auto_update_interval_hours;
+                            settings.auto_update_jitter_mins =
+                                self.settings.auto_update_jitter_mins;
+                            settings.auto_install = self.settings.auto_install;
+                            // Only save settings that give us a working client
+                            if let Err(e) = network::configure(&settings) {
+                                println!("Failed to apply network settings: {}", e);
+                                show_modal(
+                                    "Error".to_string(),
+                                    format!("Failed to apply network settings: {}", e),
+                                );
+                                return;
+                            }
+                            match settings::save(&settings) {
+                                Ok(_) => {
+                                    println!("Saved settings");
+                                    self.settings = settings;
+                                    reload_daemon_config(ctx, &self.daemon_status, &mut self.tasks);
+                                    // The sink addresses change what gets installed
+                                    refresh_hosts_file_state = true;
+                                }
+                                Err(e) => {
+                                    println!("Failed to save settings: {}", e);
+                                    show_modal(
+                                        "Error".to_string(),
+                                        format!("Failed to save settings: {}", e),
+                                    );
+                                    return;
+                                }
+                            }
+                            self.show_settings = false;
+                        }
+                        if ui.button("Cancel").clicked() {
+                            self.show_settings = false;
+                        }
+                    });
+                });
+        }
+        if self.show_schedule_settings {
+            egui::Window::new("Automatic updates")
+                .collapsible(false)
+                .resizable(true)
+                .show(ctx, |ui| {
+                    ui.label("Sources are checked while this window is open, or in the background by the daemon.");
+                    ui.checkbox(&mut self.schedule_draft.auto_update, "Update sources automatically");
+                    egui::Grid::new("schedule_settings_grid")
+                        .num_columns(2)
+                        .show(ui, |ui| {
+                            ui.label("Interval (hours):");
+                            ui.add(
+                                egui::DragValue::new(
+                                    &mut self.schedule_draft.auto_update_interval_hours,
+                                )
+                                .range(1..=24 * 30),
+                            );
+                            ui.end_row();
+                            ui.label("Random delay (minutes):");
+                            ui.add(
+                                egui::DragValue::new(&mut self.schedule_draft.auto_update_jitter_mins)
+                                    .range(0..=schedule::MAX_JITTER_MINS),
+                            );
+                            ui.end_row();
+                        });
+                    ui.label("Lists with an Expires header are checked as often as they ask, unless the source has its own interval.");
+                    ui.checkbox(
+                        &mut self.schedule_draft.auto_install,
+                        "Install the hosts file again when an update changes it",
+                    );
+                    ui.horizontal(|ui| {
+                    if ui.button("Save & Close").clicked() {
+                        let mut settings = self.settings.clone();
+                        settings.auto_update = self.schedule_draft.auto_update;
+                        settings.auto_update_interval_hours =
+                            self.schedule_draft.auto_update_interval_hours;
+                        settings.auto_update_jitter_mins = self.schedule_draft.auto_update_jitter_mins;
+                        settings.auto_install = self.schedule_draft.auto_install;
+                        match settings::save(&settings) {
+                            Ok(_) => {
+                                println!("Saved settings");
+                                self.settings = settings;
+                                reload_daemon_config(ctx, &self.daemon_status, &mut self.tasks);
+                            }
+                            Err(e) => {
+                                println!("Failed to save settings: {}", e);
+                                show_modal(
+                                    "Error".to_string(),
+                                    format!("Failed to save settings: {}", e),
+                                );
+                                return;
+                            }
+                        }
+                        self.show_schedule_settings = false;
+                    }
+                    if ui.button("Cancel").clicked() {
+                        self.show_schedule_settings = false;
+                    }
+                    });
+                });
+        }
+        if self.show_backups {
+            egui::Window::new("Backups")
+                .collapsible(false)
+                .resizable(true)
+                .show(ctx, |ui| {
+                    ui.label("The hosts file is backed up before every install and uninstall.");
+                    ui.label(format!("Snapshots of {}", self.backup_target.display()));
+                    ui.horizontal(|ui| {
+                        ui.label("Snapshots to keep (0 keeps all):");
+                        ui.add(egui::DragValue::new(&mut self.backup_settings.retention));
+                        if ui.button("Save").clicked() {
+                            match backup::save_settings(&self.backup_settings) {
+                                Ok(_) => {
+                                    println!("Saved backup settings");
+                                    reload_daemon_config(ctx, &self.daemon_status, &mut self.tasks);
+                                    backup::prune(
+                                        &self.backup_target,
+                                        self.backup_settings.retention,
+                                    );
+                                    self.backup_snapshots =
+                                        backup::list_snapshots(&self.backup_target);
+                                    self.selected_snapshot = None;
+                                    self.snapshot_diff.clear();
+                                }
+                                Err(e) => {
+                                    println!("Failed to save backup settings: {}", e);
+                                    show_modal(
+                                        "Error".to_string(),
+                                        format!("Failed to save backup settings: {}", e),
+                                    );
+                                }
+                            }
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        if let Some(i) = self.selected_snapshot {
+                            let installing = changes_hosts_file(&self.tasks);
+                            if ui
+                                .add_enabled(!installing, egui::Button::new("Restore"))
+                                .clicked()
+                            {
+                                // Snapshots only go back onto the file they were taken of
+                                let hosts_path = self.backup_target.clone();
+                                let snapshot = self.backup_snapshots[i].clone();
+                                let retention = self.backup_settings.retention;
+                                let task = Task::Restore(snapshot.path.clone());
+                                start_task(ctx, &mut self.tasks, task, move || {
+                                    // Read it before pruning, which may delete the snapshot itself
+                                    let content = backup::read_snapshot(&snapshot)?;
+                                    let content =
+                                        String::from_utf8(content).map_err(|e| e.to_string())?;
+                                    backup::snapshot_and_prune(&hosts_path, retention)?;
+                                    helper::perform(&helper::Request::restore(
+                                        hosts_path, content,
+                                    ))?;
+                                    Ok(None)
+                                });
+                            }
+                        }
+                        if ui.button("Close").clicked() {
+                            self.show_backups = false;
+                        }
+                    });
+                    if self.backup_snapshots.is_empty() {
+                        ui.label("There are no snapshots yet.");
+                    }
+                    for (i, snapshot) in self.backup_snapshots.iter().enumerate() {
+                        let datetime = DateTime::from_timestamp(snapshot.timestamp as i64, 0)
+                            .unwrap()
+                            .format("%Y-%m-%d %H:%M:%S");
+                        let label = format!(
+                            "{} ({}, {} bytes)",
+                            datetime,
+                            &snapshot.digest[..12],
+                            snapshot.size
+                        );
+                        let selected = self.selected_snapshot == Some(i);
+                        if ui.selectable_label(selected, label).clicked() {
+                            self.selected_snapshot = Some(i);
+                            let current =
+                                fs::read_to_string(&self.backup_target).unwrap_or_default();
+                            self.snapshot_diff = match backup::read_snapshot(snapshot) {
+                                Ok(content) => {
+                                    backup::diff(&String::from_utf8_lossy(&content), &current)
+                                }
+                                Err(e) => vec![e],
+                            };
+                        }
+                    }
+                    if self.selected_snapshot.is_some() {
+                        ui.separator();
+                        ui.label(format!(
+                            "{} lines differ from the current hosts file",
+                            self.snapshot_diff.len()
+                        ));
+                        egui::ScrollArea::vertical().max_height(300.0).show_rows(
+                            ui,
+                            14.0,
+                            self.snapshot_diff.len(),
+                            |ui, range| {
+                                for line in &self.snapshot_diff[range] {
+                                    ui.monospace(line);
+                                }
+                            },
+                        );
+                    }
+                });
+        }
+        // Asked for while a compile runs, they are handled once it's done
+        self.reload_pending |= reload_blocked_hosts;
+        self.refresh_pending |= refresh_hosts_file_state;
+        self.auto_install_pending |= auto_install;
+        let result = match self.compile_job.as_ref().map(Receiver::try_recv) {
+            Some(Ok(result)) => Some(result),
+            Some(Err(TryRecvError::Disconnected)) => {
+                self.compile_job = None;
+                None
+            }
+            Some(Err(TryRecvError::Empty)) | None => None,
+        };
+        if let Some(result) = result {
+            self.compile_job = None;
+            if let Some((blocked_hosts, stats, cache_index)) = result.blocked {
+                println!("Loaded {} blocked hosts", blocked_hosts.len());
+                self.blocked_hosts = blocked_hosts;
+                self.hosts_sources_stats = stats;
+                self.cache_index = cache_index;
+                self.allow_preview.clear();
+                self.known_hosts = None;
+                self.block_preview.clear();
+            }
+            self.hosts_file_state = result.state;
+            self.compiled = result.compiled;
+            if result.auto_install {
+                let settings = self.settings.clone();
+                let retention = self.backup_settings.retention;
+                let compiled = self.compiled.clone();
+                start_task(ctx, &mut self.tasks, Task::Reinstall, move || {
+                    if schedule::reinstall_if_stale(&settings, retention, compiled)? {
+                        println!("Installed hosts file");
+                    }
+                    Ok(None)
+                });
+            }
+        }
+        if self.compile_job.is_none()
+            && (self.reload_pending || self.refresh_pending || self.auto_install_pending)
+        {
+            self.start_compile(ctx);
+        }
     }
 }
@@ -0,0 +1,161 @@
+use crate::settings::Settings;
+use chrono::DateTime;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Certificate, Proxy, StatusCode};
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Longest we are willing to wait before a retry, whatever the server asks for
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+// Shared HTTP client, replaced whenever the network settings change
+static CLIENT: LazyLock<RwLock<Client>> = LazyLock::new(|| {
+    let client = build_client(&Settings::default()).unwrap_or_else(|e| {
+        eprintln!("Failed to build HTTP client: {}", e);
+        Client::new()
+    });
+    RwLock::new(client)
+});
+
+/// Returns the shared HTTP client.
+pub fn client() -> Client {
+    // Clients are reference counted, so this is cheap
+    CLIENT.read().unwrap().clone()
+}
+
+/// Builds an HTTP client from the network settings.
+pub fn build_client(settings: &Settings) -> Result<Client, String> {
+    let mut builder = Client::builder()
+        .connect_timeout(Duration::from_secs(settings.connect_timeout_secs))
+        .timeout(Duration::from_secs(settings.read_timeout_secs))
+        .user_agent(settings.user_agent.clone());
+    let proxy = settings.proxy.trim();
+    if !proxy.is_empty() {
+        let proxy = Proxy::all(proxy).map_err(|e| format!("Invalid proxy {}: {}", proxy, e))?;
+        builder = builder.proxy(proxy);
+    }
+    for path in settings.ca_certificates.lines().map(str::trim) {
+        if path.is_empty() {
+            continue;
+        }
+        let pem = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let certificate = Certificate::from_pem(&pem)
+            .map_err(|e| format!("Invalid certificate {}: {}", path, e))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+    builder
+        .build()
+        .map_err(|e| format!("Failed to build HTTP client: {}", e))
+}
+
+/// Rebuilds the shared HTTP client, keeping the current one if the settings are invalid.
+pub fn configure(settings: &Settings) -> Result<(), String> {
+    let client = build_client(settings)?;
+    *CLIENT.write().unwrap() = client;
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            attempts: settings.retry_attempts.max(1),
+            base_delay: Duration::from_secs(settings.retry_delay_secs),
+        }
+    }
+
+    // Exponential backoff with up to 50% of random jitter
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay.saturating_mul(2u32.saturating_pow(attempt));
+        // Good enough randomness for spreading out retries
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos();
+        let jitter = delay.mul_f64((nanos % 1000) as f64 / 2000.0);
+        (delay + jitter).min(MAX_RETRY_DELAY)
+    }
+}
+
+// Reads Retry-After, which is either a number of seconds or an HTTP date
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    let secs = date.timestamp() - chrono::Utc::now().timestamp();
+    Some(Duration::from_secs(secs.max(0) as u64))
+}
+
+// Sleeps in small steps so cancelling doesn't have to wait for the whole delay
+fn sleep(duration: Duration, cancelled: &AtomicBool) {
+    let step = Duration::from_millis(100);
+    let mut slept = Duration::ZERO;
+    while slept < duration && !cancelled.load(Ordering::SeqCst) {
+        thread::sleep(step);
+        slept += step;
+    }
+}
+
+/// Sends a request, retrying connection errors, timeouts and 5xx/429 responses.
+///
+/// `Retry-After` is honoured on 429 and 503 responses. The last response is
+/// returned as is if it still fails after every attempt.
+pub fn send_with_retry(
+    request: RequestBuilder,
+    policy: RetryPolicy,
+    cancelled: &AtomicBool,
+) -> Result<Response, String> {
+    let mut attempt = 0;
+    loop {
+        let last_attempt = attempt + 1 >= policy.attempts;
+        // GET requests have no body, so they can always be cloned
+        let result = request.try_clone().unwrap().send();
+        let delay = match result {
+            Ok(response) => {
+                let status = response.status();
+                let retryable = status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+                if !retryable || last_attempt {
+                    return Ok(response);
+                }
+                let server_delay = match status {
+                    StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+                        retry_after(&response)
+                    }
+                    _ => None,
+                };
+                eprintln!(
+                    "Request to {} failed with {}, retrying",
+                    response.url(),
+                    status
+                );
+                server_delay
+                    .map(|d| d.min(MAX_RETRY_DELAY))
+                    .unwrap_or_else(|| policy.delay(attempt))
+            }
+            Err(e) => {
+                let retryable = e.is_connect() || e.is_timeout();
+                if !retryable || last_attempt {
+                    return Err(e.to_string());
+                }
+                eprintln!("Request failed: {}, retrying", e);
+                policy.delay(attempt)
+            }
+        };
+        sleep(delay, cancelled);
+        if cancelled.load(Ordering::SeqCst) {
+            return Err("Cancelled".into());
+        }
+        attempt += 1;
+    }
+}
@@ -0,0 +1,51 @@
+use crate::ARGS;
+use directories::ProjectDirs;
+use std::path::{Path, PathBuf};
+
+// Where yawada keeps its config and data
+pub struct AppDirs {
+    config_dir: PathBuf,
+    data_dir: PathBuf,
+}
+
+impl AppDirs {
+    pub fn locate() -> Self {
+        if let Some(exe_dir) = portable_dir() {
+            return Self {
+                config_dir: exe_dir.join("config"),
+                data_dir: exe_dir.join("data"),
+            };
+        }
+        // Hardcode the name for now
+        let proj_dirs =
+            ProjectDirs::from("me", "tretrauit", "yawada").expect("Failed to get config directory");
+        Self {
+            config_dir: proj_dirs.config_dir().to_path_buf(),
+            data_dir: proj_dirs.data_dir().to_path_buf(),
+        }
+    }
+
+    pub fn config_dir(&self) -> &Path {
+        &self.config_dir
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+}
+
+/// Returns the directory of the executable if yawada runs in portable mode.
+///
+/// Portable mode is enabled by the `--portable` flag, the `YAWADA_PORTABLE`
+/// environment variable or a file named `portable` next to the executable.
+fn portable_dir() -> Option<PathBuf> {
+    let exe_dir = std::env::current_exe().ok()?.parent()?.to_path_buf();
+    if ARGS.portable
+        || std::env::var_os("YAWADA_PORTABLE").is_some()
+        || exe_dir.join("portable").exists()
+    {
+        Some(exe_dir)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,256 @@
+use crate::hosts;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+// Longest regex pattern we accept
+const MAX_REGEX_LEN: usize = 256;
+// Limits on the compiled regex, so a single pattern can't eat the memory or the CPU
+const REGEX_SIZE_LIMIT: usize = 256 * 1024;
+const REGEX_NEST_LIMIT: u32 = 16;
+
+/// How the host of a rule is matched against the blocked hosts.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    // Only the host itself
+    #[default]
+    Exact,
+    // The host and every subdomain of it
+    Subdomains,
+    // A pattern where * matches any run of characters and ? a single one
+    Glob,
+    // A regular expression that has to match the whole host
+    Regex,
+}
+
+impl MatchMode {
+    pub const ALL: [MatchMode; 4] = [
+        MatchMode::Exact,
+        MatchMode::Subdomains,
+        MatchMode::Glob,
+        MatchMode::Regex,
+    ];
+
+    /// Returns the name used on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MatchMode::Exact => "exact",
+            MatchMode::Subdomains => "subdomains",
+            MatchMode::Glob => "glob",
+            MatchMode::Regex => "regex",
+        }
+    }
+
+    /// Returns the name shown in the editor.
+    pub fn label(&self) -> &'static str {
+        match self {
+            MatchMode::Exact => "Exact",
+            MatchMode::Subdomains => "With subdomains",
+            MatchMode::Glob => "Glob",
+            MatchMode::Regex => "Regex",
+        }
+    }
+
+    /// Parses the name used on the command line.
+    pub fn from_name(name: &str) -> Option<MatchMode> {
+        MatchMode::ALL.into_iter().find(|mode| mode.name() == name)
+    }
+}
+
+/// Compiles a regex rule, which has to match the whole host.
+///
+/// Patterns that are too long or compile to something too big or too deeply nested are refused.
+pub fn compile_regex(pattern: &str) -> Result<Regex, String> {
+    if pattern.is_empty() {
+        return Err("The pattern is empty".into());
+    }
+    if pattern.len() > MAX_REGEX_LEN {
+        return Err(format!(
+            "The pattern is longer than {} characters",
+            MAX_REGEX_LEN
+        ));
+    }
+    RegexBuilder::new(&format!("^(?:{})$", pattern))
+        .case_insensitive(true)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .dfa_size_limit(REGEX_SIZE_LIMIT)
+        .nest_limit(REGEX_NEST_LIMIT)
+        .build()
+        .map_err(|e| format!("Invalid regex {}: {}", pattern, e))
+}
+
+/// A rule ready to be matched against hosts, regexes are compiled only once.
+pub struct Rule {
+    pattern: String,
+    mode: MatchMode,
+    regex: Option<Regex>,
+}
+
+impl Rule {
+    /// Checks a pattern and prepares it for matching.
+    pub fn new(pattern: &str, mode: MatchMode) -> Result<Rule, String> {
+        let regex = match mode {
+            MatchMode::Regex => Some(compile_regex(pattern)?),
+            _ => {
+                validate_pattern(pattern, mode)?;
+                None
+            }
+        };
+        Ok(Rule {
+            pattern: pattern.to_string(),
+            mode,
+            regex,
+        })
+    }
+
+    /// Checks if a lowercase host matches the rule.
+    pub fn matches(&self, host: &str) -> bool {
+        match &self.regex {
+            Some(regex) => regex.is_match(host),
+            None => matches(&self.pattern, self.mode, host),
+        }
+    }
+
+    /// Counts the hosts of a set the rule matches.
+    pub fn count_matches(&self, hosts: &BTreeSet<String>) -> usize {
+        match self.mode {
+            MatchMode::Exact => hosts.contains(&self.pattern) as usize,
+            _ => hosts.iter().filter(|host| self.matches(host)).count(),
+        }
+    }
+}
+
+/// Cleans up a pattern as typed, hosts are lowercased but regexes are left as they are.
+pub fn normalize_pattern(pattern: &str, mode: MatchMode) -> String {
+    let pattern = pattern.trim();
+    match mode {
+        MatchMode::Regex => pattern.to_string(),
+        _ => pattern.trim_end_matches('.').to_ascii_lowercase(),
+    }
+}
+
+/// Checks that a pattern can be used with the given match mode.
+pub fn validate_pattern(pattern: &str, mode: MatchMode) -> Result<(), String> {
+    let valid = match mode {
+        MatchMode::Exact | MatchMode::Subdomains => hosts::is_valid_hostname(pattern),
+        MatchMode::Glob => {
+            !pattern.is_empty()
+                && pattern.len() <= 253
+                && pattern
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.*?".contains(c))
+        }
+        MatchMode::Regex => return compile_regex(pattern).map(|_| ()),
+    };
+    if !valid {
+        return Err(format!("Invalid {} pattern: {}", mode.name(), pattern));
+    }
+    Ok(())
+}
+
+// Matches a pattern that doesn't need compiling, regexes go through Rule
+fn matches(pattern: &str, mode: MatchMode, host: &str) -> bool {
+    match mode {
+        MatchMode::Exact => host == pattern,
+        MatchMode::Subdomains => {
+            host == pattern
+                || host
+                    .strip_suffix(pattern)
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        }
+        MatchMode::Glob => glob_matches(pattern.as_bytes(), host.as_bytes()),
+        MatchMode::Regex => false,
+    }
+}
+
+// Wildcard matching that backtracks to the last * on a mismatch
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position of the last * in the pattern and of the text it was tried against
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the * take one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Compiles rules, logging and skipping the invalid ones.
+pub fn compile_rules<'a>(rules: impl IntoIterator<Item = (&'a str, MatchMode)>) -> Vec<Rule> {
+    rules
+        .into_iter()
+        .filter_map(|(pattern, mode)| match Rule::new(pattern, mode) {
+            Ok(rule) => Some(rule),
+            Err(e) => {
+                eprintln!("Skipping rule: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_matches_only_the_host() {
+        assert!(matches("example.com", MatchMode::Exact, "example.com"));
+        assert!(!matches("example.com", MatchMode::Exact, "a.example.com"));
+    }
+
+    #[test]
+    fn subdomains_match_on_a_label_boundary() {
+        let mode = MatchMode::Subdomains;
+        assert!(matches("example.com", mode, "example.com"));
+        assert!(matches("example.com", mode, "a.example.com"));
+        assert!(matches("example.com", mode, "a.b.example.com"));
+        assert!(!matches("example.com", mode, "badexample.com"));
+        assert!(!matches("example.com", mode, "example.com.evil.net"));
+        assert!(!matches("a.example.com", mode, "example.com"));
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob_matches(b"*.example.com", b"ads.example.com"));
+        assert!(glob_matches(b"*.example.com", b"a.b.example.com"));
+        assert!(!glob_matches(b"*.example.com", b"example.com"));
+        assert!(glob_matches(b"ad?.com", b"ads.com"));
+        assert!(!glob_matches(b"ad?.com", b"ad.com"));
+        assert!(!glob_matches(b"ad?.com", b"adss.com"));
+        assert!(glob_matches(b"ads*", b"ads"));
+        assert!(glob_matches(b"ads**", b"ads.example.com"));
+        assert!(glob_matches(b"*", b""));
+    }
+
+    #[test]
+    fn glob_backtracks_after_a_partial_match() {
+        assert!(glob_matches(b"*ab", b"aab"));
+        assert!(glob_matches(b"a*b*c", b"aXbYbZc"));
+        assert!(!glob_matches(b"a*b*c", b"aXbYbZ"));
+        assert!(glob_matches(b"*.ads.*", b"x.ads.y.ads.com"));
+        assert!(!glob_matches(b"a*a", b"a"));
+    }
+
+    #[test]
+    fn regex_rules_match_the_whole_host() {
+        let rule = Rule::new(r"ads[0-9]+\.com", MatchMode::Regex).unwrap();
+        assert!(rule.matches("ads1.com"));
+        assert!(rule.matches("ADS12.com"));
+        assert!(!rule.matches("xads1.com"));
+        assert!(!rule.matches("ads1.com.net"));
+    }
+}
@@ -0,0 +1,194 @@
+use crate::fetch::FetchStatus;
+use crate::hosts::{self, CacheIndex};
+use crate::settings::Settings;
+use crate::{backup, config, helper, install, HostsSource};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How often the scheduler looks for sources that are due.
+pub const TICK_SECS: u64 = 60;
+// A source whose scheduled update didn't go through is tried again after this long
+const RETRY_SECS: u64 = 15 * 60;
+// Lists asking to be checked more often than this are checked every hour
+const MIN_EXPIRES_SECS: u64 = 60 * 60;
+/// Longest interval that can be set on a source, in hours.
+pub const MAX_INTERVAL_HOURS: u64 = 24 * 365;
+/// Longest random delay that can be added to the interval, in minutes.
+pub const MAX_JITTER_MINS: u64 = 24 * 60;
+// Only this many lines at the top of a list are searched for an Expires header
+const MAX_HEADER_LINES: usize = 50;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Reads the update interval a list asks for in its header, e.g. `! Expires: 4 days`.
+///
+/// Returns the interval in seconds.
+pub fn parse_expires(content: &[u8]) -> Option<u64> {
+    let content = String::from_utf8_lossy(content);
+    for line in content.lines().take(MAX_HEADER_LINES) {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let comment = match line.strip_prefix('#').or_else(|| line.strip_prefix('!')) {
+            Some(comment) => comment.trim(),
+            // The header ends at the first entry
+            None => return None,
+        };
+        let value = match comment.split_once(':') {
+            Some((key, value)) if key.trim().eq_ignore_ascii_case("expires") => value,
+            _ => continue,
+        };
+        // Lists often add a note after the value, e.g. "4 days (update frequency)"
+        let mut words = value.split_whitespace();
+        let amount: u64 = words.next()?.parse().ok()?;
+        let unit = words.next().unwrap_or("days").to_ascii_lowercase();
+        // The amount comes from the list, an absurd one is ignored rather than overflowing
+        let secs = if unit.starts_with("day") {
+            amount.checked_mul(24 * 60 * 60)?
+        } else if unit.starts_with("hour") {
+            amount.checked_mul(60 * 60)?
+        } else {
+            return None;
+        };
+        return Some(secs.clamp(MIN_EXPIRES_SECS, MAX_INTERVAL_HOURS * 60 * 60));
+    }
+    None
+}
+
+/// Returns how long to wait between two checks of a source, in seconds.
+///
+/// The interval set on the source wins over the Expires header of the list,
+/// which wins over the global interval.
+pub fn interval(source: &HostsSource, settings: &Settings, index: &CacheIndex) -> u64 {
+    if source.update_interval_hours > 0 {
+        return source.update_interval_hours.min(MAX_INTERVAL_HOURS) * 60 * 60;
+    }
+    let global_hours = settings
+        .auto_update_interval_hours
+        .clamp(1, MAX_INTERVAL_HOURS);
+    index
+        .get(&source.url)
+        .and_then(|meta| meta.expires)
+        .unwrap_or(global_hours * 60 * 60)
+}
+
+// Random delay added to the interval, so the servers don't get every request at once.
+// It's derived from the last check, so it stays the same until the source is checked again.
+fn jitter(source: &HostsSource, settings: &Settings) -> u64 {
+    let max = settings.auto_update_jitter_mins.min(MAX_JITTER_MINS) * 60;
+    if max == 0 {
+        return 0;
+    }
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(source.url.as_bytes());
+    hasher.update(&source.last_checked.to_le_bytes());
+    let hash = hasher.finalize();
+    let random = u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap());
+    random % (max + 1)
+}
+
+/// Returns when a source should be checked next, as a unix timestamp.
+pub fn next_check(source: &HostsSource, settings: &Settings, index: &CacheIndex) -> u64 {
+    let last_checked = source.last_checked.max(source.last_updated);
+    if last_checked == 0 {
+        return 0;
+    }
+    last_checked
+        .saturating_add(interval(source, settings, index))
+        .saturating_add(jitter(source, settings))
+}
+
+/// Decides when sources are updated automatically.
+#[derive(Default)]
+pub struct Scheduler {
+    // When the scheduler looks at the sources again
+    next_tick: u64,
+    // When a scheduled update of each source was last started, keyed by URL
+    attempted: HashMap<String, u64>,
+}
+
+impl Scheduler {
+    /// Returns the enabled sources that are due for an update, at most once per tick.
+    pub fn poll(&mut self, sources: &[HostsSource], settings: &Settings) -> Vec<HostsSource> {
+        let now = now();
+        if now < self.next_tick {
+            return vec![];
+        }
+        self.next_tick = now + TICK_SECS;
+        let index = hosts::load_cache_index();
+        let due: Vec<HostsSource> = sources
+            .iter()
+            .filter(|s| s.enabled && !s.url.is_empty())
+            .filter(|s| next_check(s, settings, &index) <= now)
+            // Failed sources aren't checked again on every tick
+            .filter(|s| match self.attempted.get(&s.url) {
+                Some(attempted) => attempted + RETRY_SECS <= now,
+                None => true,
+            })
+            .cloned()
+            .collect();
+        for source in due.iter() {
+            self.attempted.insert(source.url.clone(), now);
+        }
+        due
+    }
+}
+
+/// Copies the update times of finished downloads into the sources.
+///
+/// Returns whether any source has new content.
+pub fn apply_results(sources: &mut [HostsSource], results: &[(String, FetchStatus)]) -> bool {
+    let mut updated = false;
+    for (url, status) in results.iter() {
+        let sources = sources.iter_mut().filter(|s| &s.url == url);
+        match status {
+            FetchStatus::Done { fetched_at, .. } => {
+                for source in sources {
+                    source.last_updated = *fetched_at;
+                    source.last_checked = *fetched_at;
+                }
+                updated = true;
+            }
+            FetchStatus::Unchanged { checked_at } => {
+                for source in sources {
+                    source.last_checked = *checked_at;
+                }
+            }
+            _ => {}
+        }
+    }
+    updated
+}
+
+/// Saves the update times of finished downloads to the config.
+///
+/// The config is read again first, so changes saved in the meantime are kept.
+pub fn record_results(results: &[(String, FetchStatus)]) -> Result<(), String> {
+    config::update(|config| {
+        apply_results(&mut config.hosts_sources, results);
+    })
+}
+
+/// Installs the compiled hosts again if the installed section is outdated.
+///
+/// Nothing is done if yawada isn't installed or its section was modified by hand.
+/// Returns whether the hosts file was written.
+pub fn reinstall_if_stale(
+    settings: &Settings,
+    retention: usize,
+    compiled: String,
+) -> Result<bool, String> {
+    let hosts_path = install::hosts_path(settings);
+    if install::read_install_state(&hosts_path, &compiled) != install::InstallState::Stale {
+        return Ok(false);
+    }
+    backup::snapshot_and_prune(&hosts_path, retention)?;
+    helper::perform(&helper::Request::install(hosts_path, compiled))?;
+    Ok(true)
+}
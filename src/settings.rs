@@ -0,0 +1,116 @@
+use crate::config;
+use serde::{Deserialize, Serialize};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub const MIN_UI_SCALE: f32 = 0.5;
+pub const MAX_UI_SCALE: f32 = 3.0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Theme {
+    // Follow the theme of the desktop
+    System,
+    Light,
+    Dark,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    // Hosts file to install into, empty means the system one
+    pub hosts_file: String,
+    // Alternate root the system hosts file is looked up in, empty means /
+    pub hosts_root: String,
+    // Proxy for all requests (http://, https:// or socks5://), empty means none
+    pub proxy: String,
+    pub connect_timeout_secs: u64,
+    pub read_timeout_secs: u64,
+    pub user_agent: String,
+    // Extra trusted root certificates, one PEM file path per line
+    pub ca_certificates: String,
+    // How many times a request is tried before giving up
+    pub retry_attempts: u32,
+    // Delay before the first retry, doubled for every following one
+    pub retry_delay_secs: u64,
+    // Check the sources for updates in the background
+    pub auto_update: bool,
+    // Default time between two checks of a source
+    pub auto_update_interval_hours: u64,
+    // Up to this many minutes are randomly added to every interval
+    pub auto_update_jitter_mins: u64,
+    // Install the hosts file again when an automatic update changes it
+    pub auto_install: bool,
+    // Address blocked hosts are pointed to
+    pub sink_address: String,
+    // Also add an IPv6 entry pointing to `sink_address_v6` for every blocked host
+    pub ipv6: bool,
+    pub sink_address_v6: String,
+    pub theme: Theme,
+    pub ui_scale: f32,
+    // Ask before closing the window
+    pub confirm_on_close: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            hosts_file: String::new(),
+            hosts_root: String::new(),
+            proxy: String::new(),
+            connect_timeout_secs: 10,
+            read_timeout_secs: 30,
+            user_agent: format!("yawada/{}", env!("CARGO_PKG_VERSION")),
+            ca_certificates: String::new(),
+            retry_attempts: 3,
+            retry_delay_secs: 2,
+            auto_update: false,
+            auto_update_interval_hours: 24,
+            auto_update_jitter_mins: 30,
+            auto_install: false,
+            sink_address: String::from("0.0.0.0"),
+            ipv6: false,
+            sink_address_v6: String::from("::"),
+            theme: Theme::System,
+            ui_scale: 1.0,
+            confirm_on_close: true,
+        }
+    }
+}
+
+/// Loads the settings from the config.
+pub fn load() -> Result<Settings, String> {
+    config::load().map(|config| config.settings)
+}
+
+/// Checks the settings the input widgets can't enforce on their own.
+///
+/// The network options are checked by building a client with them instead.
+pub fn validate(settings: &Settings) -> Result<(), String> {
+    if settings.sink_address.parse::<Ipv4Addr>().is_err() {
+        return Err(format!(
+            "The sink address {:?} isn't an IPv4 address",
+            settings.sink_address
+        ));
+    }
+    if settings.ipv6 && settings.sink_address_v6.parse::<Ipv6Addr>().is_err() {
+        return Err(format!(
+            "The IPv6 sink address {:?} isn't an IPv6 address",
+            settings.sink_address_v6
+        ));
+    }
+    if !(MIN_UI_SCALE..=MAX_UI_SCALE).contains(&settings.ui_scale) {
+        return Err(format!(
+            "The UI scale must be between {} and {}",
+            MIN_UI_SCALE, MAX_UI_SCALE
+        ));
+    }
+    if settings.user_agent.trim().is_empty() {
+        return Err("The User-Agent can't be empty".into());
+    }
+    Ok(())
+}
+
+/// Saves the settings into the config.
+pub fn save(settings: &Settings) -> Result<(), String> {
+    config::update(|config| config.settings = settings.clone())
+}
@@ -0,0 +1,81 @@
+use crate::hosts::{self, SourceFormat};
+use reqwest::StatusCode;
+
+// Fewer entries than this means the list is empty or broken
+const MIN_ENTRIES: usize = 1;
+// More entries than this is not a hosts list we want in the hosts file
+const MAX_ENTRIES: usize = 5_000_000;
+/// Largest download we accept, with room for `MAX_ENTRIES` entries of a usual length.
+pub const MAX_BODY_BYTES: u64 = 256 * 1024 * 1024;
+// Share of non-comment lines that must be valid hosts entries
+const MIN_VALID_RATIO: f64 = 0.5;
+// A new version with less than this share of the previous entries is suspicious
+const MIN_KEPT_RATIO: f64 = 0.5;
+
+/// Checks the status code and content type before the body is downloaded.
+pub fn validate_response(status: StatusCode, content_type: Option<&str>) -> Result<(), String> {
+    if !status.is_success() {
+        return Err(format!("The server responded with {}", status));
+    }
+    if let Some(content_type) = content_type {
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        if mime == "text/html" || mime == "application/xhtml+xml" {
+            return Err(format!("The server sent a web page ({})", mime));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a downloaded body is a usable hosts list, returns its number of entries.
+///
+/// `previous_entries` is the number of entries of the cached version, if there is one.
+/// Local files and inline sources are written by the user, so an empty list or a big drop
+/// is what they asked for and only `remote` content goes through those checks.
+pub fn validate_content(
+    body: &[u8],
+    format: SourceFormat,
+    previous_entries: Option<usize>,
+    remote: bool,
+) -> Result<usize, String> {
+    let content = String::from_utf8_lossy(body);
+    let start = content.trim_start().to_ascii_lowercase();
+    if start.starts_with("<!doctype") || start.starts_with("<html") {
+        return Err("The content is a web page, not a hosts list".into());
+    }
+    let parsed = hosts::parse_source(&content, format);
+    // Exception rules count too, a list may consist of nothing else
+    let entries = parsed.hosts.len() + parsed.allowed.len();
+    if parsed.lines > 0 {
+        let valid = parsed.lines - parsed.rejected_lines;
+        let ratio = valid as f64 / parsed.lines as f64;
+        if ratio < MIN_VALID_RATIO {
+            return Err(format!(
+                "Only {} of {} lines are valid hosts entries",
+                valid, parsed.lines
+            ));
+        }
+    }
+    if remote && entries < MIN_ENTRIES {
+        return Err(format!("The list has only {} entries", entries));
+    }
+    if entries > MAX_ENTRIES {
+        return Err(format!(
+            "The list has {} entries, more than the limit of {}",
+            entries, MAX_ENTRIES
+        ));
+    }
+    if let Some(previous_entries) = previous_entries.filter(|_| remote) {
+        if (entries as f64) < previous_entries as f64 * MIN_KEPT_RATIO {
+            return Err(format!(
+                "The list dropped from {} to {} entries",
+                previous_entries, entries
+            ));
+        }
+    }
+    Ok(entries)
+}
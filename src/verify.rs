@@ -0,0 +1,78 @@
+use crate::{hosts, network, HostsSource};
+use minisign_verify::{PublicKey, Signature};
+use sha2::{Digest, Sha256};
+use std::fs;
+
+/// Returns the URL of the detached minisign signature of a source.
+pub fn signature_url(source: &HostsSource) -> String {
+    if source.signature_url.is_empty() {
+        format!("{}.minisig", source.url)
+    } else {
+        source.signature_url.clone()
+    }
+}
+
+// Checks the content against a pinned `blake3:<hex>` or `sha256:<hex>` digest
+fn verify_checksum(checksum: &str, body: &[u8]) -> Result<(), String> {
+    let (algorithm, expected) = checksum
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid checksum {}, expected <algorithm>:<hex>", checksum))?;
+    let actual = match algorithm.to_ascii_lowercase().as_str() {
+        "blake3" => blake3::hash(body).to_hex().to_string(),
+        "sha256" => Sha256::digest(body)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+        _ => return Err(format!("Unsupported checksum algorithm {}", algorithm)),
+    };
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(format!("The {} checksum doesn't match", algorithm));
+    }
+    Ok(())
+}
+
+// Checks the content against its detached minisign signature
+fn verify_signature(source: &HostsSource, body: &[u8]) -> Result<(), String> {
+    let key = source.public_key.trim();
+    // Accept both the bare base64 key and the content of a .pub file
+    let public_key = if key.contains('\n') {
+        PublicKey::decode(key)
+    } else {
+        PublicKey::from_base64(key)
+    }
+    .map_err(|e| format!("Invalid public key: {}", e))?;
+    let url = signature_url(source);
+    let signature = match hosts::location(&url) {
+        hosts::Location::Http => network::client()
+            .get(&url)
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.text())
+            .map_err(|e| e.to_string()),
+        hosts::Location::File(path) => fs::read_to_string(path).map_err(|e| e.to_string()),
+        hosts::Location::Inline => Err("Inline sources can't be signed".to_string()),
+    }
+    .map_err(|e| format!("Failed to fetch signature {}: {}", url, e))?;
+    let signature =
+        Signature::decode(&signature).map_err(|e| format!("Invalid signature: {}", e))?;
+    public_key
+        .verify(body, &signature, false)
+        .map_err(|e| format!("Signature verification failed: {}", e))
+}
+
+/// Verifies the content of a source with its pinned checksum and public key.
+///
+/// Returns whether anything was verified, sources without either are left unverified.
+pub fn verify(source: &HostsSource, body: &[u8]) -> Result<bool, String> {
+    let mut verified = false;
+    if !source.checksum.trim().is_empty() {
+        verify_checksum(&source.checksum, body)?;
+        verified = true;
+    }
+    if !source.public_key.trim().is_empty() {
+        verify_signature(source, body)?;
+        verified = true;
+    }
+    Ok(verified)
+}